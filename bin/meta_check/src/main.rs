@@ -0,0 +1,34 @@
+use std::path::PathBuf;
+
+use fo_meta_server::{
+    config,
+    database::{check, SledDb},
+    sled,
+};
+
+fn main() {
+    let repair = std::env::args().skip(1).any(|arg| arg == "--repair");
+    let _config = config::setup().expect("config.toml file");
+
+    let mut db_path = PathBuf::new();
+    db_path.push("db");
+    db_path.push("sled");
+    let db = sled::open(db_path).expect("Can't open sled database");
+    let sled_db = SledDb::new(db);
+
+    let report = check::check(&sled_db.root, repair).expect("Can't check database");
+    for problem in &report.problems {
+        println!(
+            "{}: {}{}",
+            problem.key,
+            problem.description,
+            if problem.repaired { " (repaired)" } else { "" }
+        );
+    }
+    println!(
+        "Scanned: {}, problems: {}, repaired: {}",
+        report.scanned,
+        report.problems.len(),
+        report.repaired()
+    );
+}
@@ -0,0 +1,264 @@
+//! Stand-in for Discord OAuth2 and user info endpoints, so login can be tested offline.
+//! Any configured user can be picked on the authorize page, there are no passwords.
+//! Also used by the tests of the meta server, see `configure`.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Responder};
+use parking_lot::Mutex;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+
+const TOKEN_TTL: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub addr: SocketAddr,
+    pub client_id: String,
+    pub secret: String,
+    pub users: Vec<User>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    #[serde(default = "User::default_discriminator")]
+    pub discriminator: String,
+    #[serde(default)]
+    pub avatar: Option<String>,
+    /// Nickname in the main guild, for tests of the meta server
+    #[serde(default, skip_serializing)]
+    pub nick: Option<String>,
+    /// Names of roles in the main guild, for tests of the meta server
+    #[serde(default, skip_serializing)]
+    pub roles: Vec<String>,
+}
+impl User {
+    pub fn new(id: &str, username: &str) -> Self {
+        User {
+            id: id.into(),
+            username: username.into(),
+            discriminator: Self::default_discriminator(),
+            avatar: None,
+            nick: None,
+            roles: vec![],
+        }
+    }
+
+    fn default_discriminator() -> String {
+        "0000".into()
+    }
+}
+
+struct Grant {
+    user_id: String,
+    redirect_uri: String,
+}
+
+pub struct State {
+    config: Config,
+    codes: Mutex<HashMap<String, Grant>>,
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+impl State {
+    pub fn new(config: Config) -> Self {
+        State {
+            config,
+            codes: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn user(&self, id: &str) -> Option<&User> {
+        self.config.users.iter().find(|user| user.id == id)
+    }
+}
+
+fn random_hex() -> String {
+    let random: [u8; 16] = rand::random();
+    random.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    client_id: String,
+    redirect_uri: String,
+    #[serde(default)]
+    state: String,
+    /// Picked on the page, the page is shown while it's missing
+    user: Option<String>,
+}
+
+async fn authorize(state: web::Data<State>, query: web::Query<AuthorizeQuery>) -> HttpResponse {
+    if query.client_id != state.config.client_id {
+        return HttpResponse::BadRequest().body("Unknown client_id");
+    }
+    let user_id = match &query.user {
+        Some(user_id) if state.user(user_id).is_some() => user_id.clone(),
+        Some(_) => return HttpResponse::BadRequest().body("Unknown user"),
+        None => {
+            let links: String = state
+                .config
+                .users
+                .iter()
+                .map(|user| {
+                    format!(
+                        r#"<li><a href="?client_id={}&redirect_uri={}&state={}&user={}">{} ({})</a></li>"#,
+                        encode(&query.client_id),
+                        encode(&query.redirect_uri),
+                        encode(&query.state),
+                        encode(&user.id),
+                        user.username,
+                        user.id
+                    )
+                })
+                .collect();
+            return HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(format!("<h1>Log in as:</h1><ul>{}</ul>", links));
+        }
+    };
+
+    let code = random_hex();
+    state.codes.lock().insert(
+        code.clone(),
+        Grant {
+            user_id,
+            redirect_uri: query.redirect_uri.clone(),
+        },
+    );
+    let separator = if query.redirect_uri.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let location = format!(
+        "{}{}code={}&state={}",
+        query.redirect_uri,
+        separator,
+        code,
+        encode(&query.state)
+    );
+    HttpResponse::Found()
+        .append_header((header::LOCATION, location))
+        .finish()
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    code: String,
+    redirect_uri: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: u64,
+    scope: &'static str,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: &'static str,
+}
+
+/// Client credentials from the form or from `Authorization: Basic` header
+fn client_credentials(req: &HttpRequest, form: &TokenForm) -> Option<(String, String)> {
+    if let (Some(id), Some(secret)) = (&form.client_id, &form.client_secret) {
+        return Some((id.clone(), secret.clone()));
+    }
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let decoded = base64::decode(value.strip_prefix("Basic ")?).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+    let decode = |value: &str| {
+        percent_encoding::percent_decode_str(value)
+            .decode_utf8()
+            .ok()
+            .map(|value| value.into_owned())
+    };
+    Some((decode(id)?, decode(secret)?))
+}
+
+async fn token(
+    req: HttpRequest,
+    state: web::Data<State>,
+    form: web::Form<TokenForm>,
+) -> HttpResponse {
+    let error = |error| HttpResponse::BadRequest().json(ErrorResponse { error });
+    let credentials = client_credentials(&req, &form);
+    let config = &state.config;
+    if credentials != Some((config.client_id.clone(), config.secret.clone())) {
+        return HttpResponse::Unauthorized().json(ErrorResponse {
+            error: "invalid_client",
+        });
+    }
+    if form.grant_type != "authorization_code" {
+        return error("unsupported_grant_type");
+    }
+    let grant = match state.codes.lock().remove(&form.code) {
+        Some(grant) => grant,
+        None => return error("invalid_grant"),
+    };
+    if let Some(redirect_uri) = &form.redirect_uri {
+        if *redirect_uri != grant.redirect_uri {
+            return error("invalid_grant");
+        }
+    }
+
+    let access_token = random_hex();
+    state
+        .tokens
+        .lock()
+        .insert(access_token.clone(), grant.user_id);
+    HttpResponse::Ok().json(TokenResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: TOKEN_TTL,
+        scope: "identify",
+    })
+}
+
+async fn user_info(req: HttpRequest, state: web::Data<State>) -> HttpResponse {
+    let user = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| state.tokens.lock().get(token.trim()).cloned())
+        .and_then(|user_id| state.user(&user_id).cloned());
+    match user {
+        Some(user) => HttpResponse::Ok().json(user),
+        None => HttpResponse::Unauthorized().json(ErrorResponse {
+            error: "invalid_token",
+        }),
+    }
+}
+
+async fn index(state: web::Data<State>) -> impl Responder {
+    let base = format!("http://{}/api", state.config.addr);
+    format!(
+        "Use in config.toml:\n\
+        oauth2 = {{ client_id = {:?}, secret = {:?}, authorize_url = \"{}/oauth2/authorize\", \
+        token_url = \"{}/oauth2/token\", user_info_url = \"{}/users/@me\" }}\n",
+        state.config.client_id, state.config.secret, base, base, base
+    )
+}
+
+/// Routes of the provider, `web::Data<State>` has to be in app data
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/").route(web::get().to(index)))
+        .service(web::resource("/api/oauth2/authorize").route(web::get().to(authorize)))
+        .service(web::resource("/api/oauth2/token").route(web::post().to(token)))
+        .service(web::resource("/api/users/@me").route(web::get().to(user_info)));
+}
@@ -0,0 +1,23 @@
+use actix_web::{web, App, HttpServer};
+use fo_meta_fake_oauth::{configure, Config, State};
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "fake_oauth.toml".into());
+    let toml = std::fs::read_to_string(&path).expect("fake_oauth.toml file");
+    let config: Config = toml::from_str(&toml).expect("Valid fake_oauth.toml");
+    let addr = config.addr;
+    println!(
+        "Fake OAuth provider on http://{}/ with {} users",
+        addr,
+        config.users.len()
+    );
+
+    let state = web::Data::new(State::new(config));
+    HttpServer::new(move || App::new().app_data(state.clone()).configure(configure))
+        .bind(addr)?
+        .run()
+        .await
+}
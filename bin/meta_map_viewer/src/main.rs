@@ -13,8 +13,11 @@ fn main() -> std::io::Result<()> {
 
     let items = fo_proto_format::build_btree(&config.paths.proto_items);
 
-    let fo_data = fo_data::FoData::init(&config.paths.game_client, &config.paths.palette)
-        .expect("FoData loading");
+    let fo_data = fo_data::FoData::init(
+        &config.paths.game_client,
+        config.paths.palette.as_ref().expect("palette path"),
+    )
+    .expect("FoData loading");
     println!(
         "FoData loaded, archives: {}, files: {}",
         fo_data.retriever.data().count_archives(),
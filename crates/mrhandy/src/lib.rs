@@ -6,7 +6,7 @@ pub use serenity::{
     model::guild::{Guild, Role},
 };
 use serenity::{
-    all::{ActivityData, GuildId},
+    all::{ActivityData, GuildId, UserId},
     cache::Cache,
     gateway::ShardManager,
     http::Http,
@@ -85,6 +85,36 @@ impl MrHandy {
         Ok(())
     }
 
+    /// Name, nick and roles of the member from the gateway cache
+    pub async fn cached_member_roles(&self, user_id: u64) -> Result<MemberRoles, &'static str> {
+        self.with_guild_member(user_id, |guild, member| {
+            let roles = Self::get_roles(guild, member, |role| {
+                (u64::from(role.id), role.name.to_string())
+            });
+            MemberRoles::new(member, roles)
+        })
+        .await
+    }
+
+    /// Same as `cached_member_roles`, but asks Discord REST API, so it works while the cache is cold
+    pub async fn fetch_member_roles(&self, user_id: u64) -> Result<MemberRoles, Error> {
+        let member = self
+            .http
+            .get_member(self.main_guild_id, UserId::new(user_id))
+            .await
+            .map_err(Error::Serenity)?;
+        let roles = self
+            .http
+            .get_guild_roles(self.main_guild_id)
+            .await
+            .map_err(Error::Serenity)?
+            .into_iter()
+            .filter(|role| member.roles.contains(&role.id))
+            .map(|role| (u64::from(role.id), role.name.to_string()))
+            .collect();
+        Ok(MemberRoles::new(&member, roles))
+    }
+
     pub fn get_roles<O, F: Fn(&Role) -> O>(guild: &Guild, member: &Member, fun: F) -> Vec<O> {
         member
             .roles
@@ -152,11 +182,41 @@ pub enum ConditionColor {
     Red,
 }
 
+#[derive(Debug)]
 pub enum Error {
     NoMainGuild,
     ChannelNotFound(String),
     Serenity(serenity::Error),
 }
+impl Error {
+    /// Discord answered that the user isn't a member of the guild, unlike network or server errors
+    pub fn is_unknown_member(&self) -> bool {
+        match self {
+            Error::Serenity(serenity::Error::Http(err)) => {
+                err.status_code() == Some(serenity::http::StatusCode::NOT_FOUND)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Member data needed to resolve ranks, detached from the cache
+#[derive(Debug, Clone)]
+pub struct MemberRoles {
+    pub name: String,
+    pub nick: Option<String>,
+    /// Ids and names of member's roles
+    pub roles: Vec<(u64, String)>,
+}
+impl MemberRoles {
+    fn new(member: &Member, roles: Vec<(u64, String)>) -> Self {
+        MemberRoles {
+            name: member.user.name.to_string(),
+            nick: member.nick.as_ref().map(|nick| nick.to_string()),
+            roles,
+        }
+    }
+}
 
 pub struct MemberInfo {
     pub nick: Option<FixedString>,
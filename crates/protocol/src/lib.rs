@@ -3,7 +3,7 @@ use std::ffi::CString;
 use serde::{Deserialize, Serialize};
 
 pub const HANDSHAKE: u16 = 0xBABA;
-pub const VERSION: u16 = 6;
+pub const VERSION: u16 = 7;
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum GameServerToMetaServer {
     PlayerConnected(u32),
@@ -59,9 +59,24 @@ pub struct ServerStatus {
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum MetaServerToGameServer {
-    UpdateCharLeaf { id: u32, ver: u32, secret: u32 },
+    UpdateCharLeaf {
+        id: u32,
+        ver: u32,
+        secret: u32,
+    },
     SendKeyToPlayer(u32, [u32; 3]),
-    SendConfig { player_id: u32, url: CString },
-    StartGame { player_id: u32 },
+    SendConfig {
+        player_id: u32,
+        url: CString,
+    },
+    StartGame {
+        player_id: u32,
+    },
     Nop,
+    UpdateCharImage {
+        id: u32,
+        slot: CString,
+        ver: u32,
+        secret: u32,
+    },
 }
@@ -1,4 +1,4 @@
-use std::{convert::TryInto, ffi::CStr, sync::Arc};
+use std::{ffi::CStr, sync::Arc};
 
 use actix_codec::{Decoder, Encoder, Framed};
 use actix_rt::net::TcpStream;
@@ -18,7 +18,9 @@ use parking_lot::RwLock;
 use serde::Serialize;
 
 use crate::{
-    database::{ownership, CharTrunk, Root, VersionedError},
+    config::Limit,
+    database::{ownership, Root, VersionedError},
+    rate_limit::TokenBucket,
     utils::blocking,
     web::AppState,
 };
@@ -242,6 +244,7 @@ async fn start_impl(data: BridgeData) -> Server {
                 // service for converting incoming TcpStream to a SslStream<TcpStream>
                 fn_service(move |tcp_stream: TcpStream| {
                     let data = data.clone();
+                    let flood = flood_filter(data.state.config.rate_limit.bridge.clone());
 
                     let (sender, receiver) = channel(128);
                     data.bridge().set_sender(sender);
@@ -252,6 +255,7 @@ async fn start_impl(data: BridgeData) -> Server {
                     futures::stream::select(
                         stream
                             .map_err(BridgeError::Bincode)
+                            .try_filter(flood)
                             //.filter_map(handle_message)
                             .and_then(move |msg| handle_message_async(msg, data.clone()))
                             .boxed(),
@@ -280,29 +284,14 @@ async fn handle_message_async(msg_in: MsgIn, data: BridgeData) -> BridgeResult<M
         }),
         MsgIn::PlayerAuth(cr_id) => {
             let root = data.root().clone();
+            let ttl = data.state.config.authkey.ttl();
+            let rotate = data.state.config.authkey.rotate_on_auth;
             let fut = blocking(move || {
-                let owner =
-                    ownership::get_ownership(&root, cr_id).map_err(BridgeError::Versioned)?;
-                if owner.is_some() {
-                    return Ok(None);
-                }
-                let default: [u8; 12] = loop {
-                    let random = rand::random();
-                    if random != [0u8; 12] {
-                        break random;
-                    }
-                };
-                let authkey = root
-                    .trunk(cr_id, None, CharTrunk::default())
-                    .get_bare_branch_or_default("authkey", &default[..], |val| val.len() == 12)
-                    .map_err(BridgeError::Versioned)?;
-                let auth_bytes = match authkey {
-                    Some(authkey) => {
-                        let slice = authkey.as_ref();
-                        let bytes: [u8; 12] = slice.try_into().map_err(|_| BridgeError::TryInto)?;
-                        bytes
-                    }
-                    None => default,
+                let auth_bytes = match ownership::issue_authkey(&root, cr_id, ttl, rotate)
+                    .map_err(BridgeError::Versioned)?
+                {
+                    Some(auth_bytes) => auth_bytes,
+                    None => return Ok(None),
                 };
                 let authkey: [u32; 3] = bytemuck::cast(auth_bytes);
                 Ok(Some(authkey))
@@ -324,6 +313,21 @@ async fn handle_message_async(msg_in: MsgIn, data: BridgeData) -> BridgeResult<M
     }
 }
 
+/// Drops messages over the limit of the connection, so a misbehaving game server
+/// can't flood the database and Discord
+fn flood_filter(limit: Limit) -> impl FnMut(&MsgIn) -> future::Ready<bool> {
+    let mut bucket = TokenBucket::new(&limit);
+    let mut flooding = false;
+    move |_msg| {
+        let pass = bucket.take(&limit).is_ok();
+        if !pass && !flooding {
+            eprintln!("Bridge flood, dropping messages from the game server");
+        }
+        flooding = !pass;
+        future::ready(pass)
+    }
+}
+
 fn drop_nop(msg_out: &MsgOut) -> impl Future<Output = bool> {
     future::ready(match msg_out {
         MsgOut::Nop => false,
@@ -1,4 +1,4 @@
-use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf};
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, time::Duration};
 
 use actix_web::cookie::Key as CookieKey;
 use serde::Deserialize;
@@ -106,6 +106,27 @@ impl Host {
 pub struct OAuth {
     pub client_id: String,
     pub secret: String,
+    /// Endpoints of the provider, Discord by default, can point to `meta_fake_oauth`
+    #[serde(default = "OAuth::default_authorize_url")]
+    pub authorize_url: String,
+    #[serde(default = "OAuth::default_token_url")]
+    pub token_url: String,
+    /// Returns JSON with `id` of the user who authorized the access token
+    #[serde(default = "OAuth::default_user_info_url")]
+    pub user_info_url: String,
+}
+impl OAuth {
+    fn default_authorize_url() -> String {
+        "https://discordapp.com/api/oauth2/authorize".into()
+    }
+
+    fn default_token_url() -> String {
+        "https://discordapp.com/api/oauth2/token".into()
+    }
+
+    fn default_user_info_url() -> String {
+        "https://discordapp.com/api/users/@me".into()
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -113,12 +134,28 @@ pub struct Bot {
     pub token: String,
 }
 
+/// Discord role, either its id or its name
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum RoleMatcher {
+    Id(u64),
+    Name(String),
+}
+impl RoleMatcher {
+    pub fn matches(&self, id: u64, name: &str) -> bool {
+        match self {
+            RoleMatcher::Id(role_id) => *role_id == id,
+            RoleMatcher::Name(role_name) => role_name == name,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Roles {
-    pub admin: String,
-    pub developer: String,
-    pub gamemaster: String,
-    pub player: String,
+    pub admin: RoleMatcher,
+    pub developer: RoleMatcher,
+    pub gamemaster: RoleMatcher,
+    pub player: RoleMatcher,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -127,6 +164,51 @@ pub struct Discord {
     pub oauth2: OAuth,
     pub bot: Bot,
     pub roles: Roles,
+    /// Permissions by rank (`player`, `gamemaster`, `developer`, `admin`),
+    /// entry for a rank replaces its built-in defaults
+    #[serde(default)]
+    pub permissions: BTreeMap<String, Vec<String>>,
+    /// Extra permissions by role id or name, added to the ones of member's ranks.
+    /// Names of ranks aren't allowed here, so a role can't pass for a rank.
+    #[serde(default)]
+    pub role_permissions: BTreeMap<String, Vec<String>>,
+    /// Seconds to reuse looked up roles of a user before asking Discord again
+    #[serde(default = "Discord::default_rank_cache_ttl")]
+    pub rank_cache_ttl: u64,
+    /// Allow login with Discord OAuth, the bot keeps working without it
+    #[serde(default = "Discord::default_login")]
+    pub login: bool,
+}
+impl Discord {
+    /// Keys of `permissions`, same as `Rank::config_key`
+    pub const RANK_KEYS: [&'static str; 4] = ["player", "gamemaster", "developer", "admin"];
+
+    fn default_rank_cache_ttl() -> u64 {
+        5 * 60
+    }
+
+    fn default_login() -> bool {
+        true
+    }
+
+    /// Ranks and roles are kept apart, so a role named like a rank can't take its permissions
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(key) = self
+            .permissions
+            .keys()
+            .find(|key| !Self::RANK_KEYS.contains(&key.as_str()))
+        {
+            return Err(ConfigError::RankPermissions(key.clone()));
+        }
+        if let Some(key) = self
+            .role_permissions
+            .keys()
+            .find(|key| Self::RANK_KEYS.contains(&key.as_str()))
+        {
+            return Err(ConfigError::RolePermissions(key.clone()));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -169,8 +251,8 @@ pub struct Paths {
     pub working_dir: PathBuf,  // "../web"
     #[cfg(feature = "fo_data")]
     pub game_client: PathBuf, // "../../CL4RP"
-    #[cfg(feature = "fo_data")]
-    pub palette: PathBuf, // "../../FO4RP/proto/items/items.lst"
+    #[serde(default)]
+    pub palette: Option<PathBuf>, // "../../FO4RP/COLOR.PAL"
     pub private: PrivatePaths, // ["../../FO4RP/logs", "../../FO4RP/dumps", "../../FO4RP/save"]
 }
 
@@ -194,8 +276,22 @@ pub enum Base64 {
 #[derive(Debug, Deserialize, Clone)]
 pub struct Session {
     cookie_key: Option<Base64>,
+    /// Seconds of inactivity before server side session expires
+    #[serde(default = "Session::default_ttl")]
+    pub ttl: u64,
+    /// Send cookies only over HTTPS, by default when `host.web_tls` is set;
+    /// enable it explicitly behind a TLS-terminating proxy
+    pub cookie_secure: Option<bool>,
 }
 impl Session {
+    fn default_ttl() -> u64 {
+        30 * 24 * 60 * 60
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl)
+    }
+
     fn setup_key(&mut self) -> Result<(), ConfigError> {
         if let Some(key) = &mut self.cookie_key {
             if let Base64::String(string) = &*key {
@@ -258,6 +354,195 @@ impl Default for Bridge {
     }
 }
 
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthKey {
+    /// Seconds before issued authkey expires, 0 means never
+    pub ttl: u64,
+    /// Issue new authkey on every `PlayerAuth` from the game
+    pub rotate_on_auth: bool,
+    /// Failed claims of the same character allowed during `throttle_window`
+    pub max_claim_failures: u32,
+    pub throttle_window: u64,
+    /// Allow login into local account of the character with its authkey, without Discord
+    pub login: bool,
+    /// Permissions of local accounts
+    pub login_permissions: Vec<String>,
+}
+impl AuthKey {
+    pub fn ttl(&self) -> Option<Duration> {
+        if self.ttl == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.ttl))
+        }
+    }
+
+    pub fn throttle_window(&self) -> Duration {
+        Duration::from_secs(self.throttle_window)
+    }
+}
+impl Default for AuthKey {
+    fn default() -> Self {
+        Self {
+            ttl: 24 * 60 * 60,
+            rotate_on_auth: false,
+            max_claim_failures: 5,
+            throttle_window: 15 * 60,
+            login: false,
+            login_permissions: vec!["own_characters".into()],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKey {
+    Ip,
+    /// Falls back to IP for anonymous requests
+    User,
+    IpAndUser,
+}
+
+/// Token bucket: `burst` requests at once, refilled at `per_minute` rate, 0 disables
+#[derive(Debug, Deserialize, Clone)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_minute: u32,
+    #[serde(default = "Limit::default_key")]
+    pub key: LimitKey,
+}
+impl Limit {
+    fn new(burst: u32, per_minute: u32, key: LimitKey) -> Self {
+        Self {
+            burst,
+            per_minute,
+            key,
+        }
+    }
+
+    fn default_key() -> LimitKey {
+        LimitKey::Ip
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.burst > 0 && self.per_minute > 0
+    }
+
+    pub fn per_second(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimit {
+    /// Take client IP from `X-Forwarded-For`, only behind a reverse proxy that sets it
+    pub trust_forwarded: bool,
+    pub login: Limit,
+    pub auth: Limit,
+    /// Authkey checks, both claims and authkey logins
+    pub claim: Limit,
+    pub upload: Limit,
+    /// Messages from the game server, the key is ignored
+    pub bridge: Limit,
+}
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            trust_forwarded: false,
+            login: Limit::new(10, 10, LimitKey::Ip),
+            auth: Limit::new(10, 10, LimitKey::Ip),
+            claim: Limit::new(5, 5, LimitKey::Ip),
+            upload: Limit::new(5, 10, LimitKey::User),
+            bridge: Limit::new(1000, 60000, LimitKey::Ip),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Avatar {
+    /// Keep uploads pending until a game master approves them
+    pub moderation: bool,
+    /// Number of served images kept in memory, 0 disables the cache
+    pub cache_size: usize,
+    /// Reduce uploads to the game palette from `paths.palette`
+    pub quantize: bool,
+    pub dither: Dither,
+}
+impl Default for Avatar {
+    fn default() -> Self {
+        Self {
+            moderation: false,
+            cache_size: 1024,
+            quantize: false,
+            dither: Dither::FloydSteinberg,
+        }
+    }
+}
+
+/// Additional per-character image, uploaded and versioned like avatar
+#[derive(Debug, Deserialize, Clone)]
+pub struct ImageSlot {
+    /// Lowercase latin letters, digits and underscores, used in urls and database keys
+    pub name: String,
+    /// Uploads are cropped and scaled to exactly these dimensions
+    pub width: u32,
+    pub height: u32,
+    #[serde(default = "ImageSlot::default_max_bytes")]
+    pub max_bytes: usize,
+    /// Any of "png", "jpeg", "webp", "gif"
+    #[serde(default = "ImageSlot::default_formats")]
+    pub formats: Vec<String>,
+}
+impl ImageSlot {
+    const FORMATS: [&'static str; 4] = ["png", "jpeg", "webp", "gif"];
+    const MAX_SIZE: u32 = 1024;
+    /// Suffixes of the slot's own database branches, a slot named like this would share them
+    const RESERVED_SUFFIXES: [&'static str; 4] = ["_secret", "_ver", "_time", "_status"];
+
+    fn default_max_bytes() -> usize {
+        2 * 1024 * 1024
+    }
+
+    fn default_formats() -> Vec<String> {
+        vec!["png".into(), "jpeg".into()]
+    }
+
+    /// `previous` are slots declared before this one, names must be unique
+    fn validate(&self, previous: &[ImageSlot]) -> Result<(), ConfigError> {
+        let name_ok = !self.name.is_empty()
+            && self
+                .name
+                .bytes()
+                .all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'_')
+            && !Self::RESERVED_SUFFIXES
+                .iter()
+                .any(|suffix| self.name.ends_with(suffix))
+            && !previous.iter().any(|other| other.name == self.name);
+        let size_ok = (1..=Self::MAX_SIZE).contains(&self.width)
+            && (1..=Self::MAX_SIZE).contains(&self.height);
+        let formats_ok = !self.formats.is_empty()
+            && self
+                .formats
+                .iter()
+                .all(|format| Self::FORMATS.contains(&format.as_str()));
+        if name_ok && size_ok && formats_ok && self.max_bytes > 0 {
+            Ok(())
+        } else {
+            Err(ConfigError::ImageSlot(self.name.clone()))
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub host: Host,
@@ -266,6 +551,45 @@ pub struct Config {
     pub session: Session,
     #[serde(default)]
     pub bridge: Bridge,
+    #[serde(default)]
+    pub authkey: AuthKey,
+    #[serde(default)]
+    pub avatar: Avatar,
+    #[serde(default)]
+    pub image_slots: Vec<ImageSlot>,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+}
+
+impl Config {
+    pub fn cookie_secure(&self) -> bool {
+        self.session
+            .cookie_secure
+            .unwrap_or_else(|| self.host.web_tls.is_some())
+    }
+
+    pub fn discord_login(&self) -> bool {
+        self.discord.as_ref().map_or(false, |discord| discord.login)
+    }
+
+    pub fn image_slot(&self, name: &str) -> Option<&ImageSlot> {
+        self.image_slots.iter().find(|slot| slot.name == name)
+    }
+
+    /// Parsed and validated, private folders are resolved from the current directory,
+    /// other paths are left as they are
+    pub fn from_toml(toml: &str) -> Result<Config, ConfigError> {
+        let mut config: Config = toml::from_str(toml).map_err(ConfigError::Toml)?;
+        config.session.setup_key()?;
+        if let Some(discord) = &config.discord {
+            discord.validate()?;
+        }
+        for (index, slot) in config.image_slots.iter().enumerate() {
+            slot.validate(&config.image_slots[..index])?;
+        }
+        config.paths.private.setup()?;
+        Ok(config)
+    }
 }
 
 #[derive(Debug)]
@@ -278,6 +602,9 @@ pub enum ConfigError {
     NoSessionKey,
     SessionKeyDecode(base64::DecodeError),
     SessionKeyLengthLessThan32(usize),
+    ImageSlot(String),
+    RankPermissions(String),
+    RolePermissions(String),
 }
 
 fn canon(path: &mut PathBuf) -> Result<(), ConfigError> {
@@ -294,8 +621,7 @@ pub fn setup() -> Result<Config, ConfigError> {
     }
 
     let toml = std::fs::read_to_string("./config.toml").map_err(ConfigError::Io)?;
-    let mut config: Config = toml::from_str(&toml).map_err(ConfigError::Toml)?;
-    config.session.setup_key()?;
+    let mut config = Config::from_toml(&toml)?;
 
     let paths = &mut config.paths;
     canon(&mut paths.save_clients)?;
@@ -306,9 +632,9 @@ pub fn setup() -> Result<Config, ConfigError> {
     canon(&mut paths.working_dir)?;
     #[cfg(feature = "fo_data")]
     canon(&mut paths.game_client)?;
-    #[cfg(feature = "fo_data")]
-    canon(&mut paths.palette)?;
-    paths.private.setup()?;
+    if let Some(palette) = &mut paths.palette {
+        canon(palette)?;
+    }
 
     std::env::set_current_dir(&paths.working_dir).map_err(ConfigError::Io)?;
     Ok(config)
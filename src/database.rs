@@ -4,14 +4,23 @@ mod versioned;
 pub use self::versioned::VersionedError;
 
 mod tree;
-pub use tree::{Leaf, Root};
+pub use tree::{Leaf, LeafInfo, Root};
 
 mod character;
-pub use character::CharTrunk;
+pub use character::{image_characters, CharTrunk};
 
+mod user;
+pub use user::{CachedRoles, UserSettings, UserTrunk};
+
+pub mod audit;
+pub mod check;
+pub mod moderation;
 pub mod ownership;
+pub mod session;
+pub mod token;
 
 mod tools;
+pub use tools::{new_secret, unix_now};
 
 #[derive(Clone)]
 pub struct SledDb {
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+
+use super::{tools::unix_now, Root, VersionedError};
+
+pub(super) const AUDIT_PREFIX: &str = "audit/";
+
+/// Sensitive action of a staff member, like looking at the site as another user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub actor: u64,
+    pub action: String,
+    pub details: String,
+    pub timestamp: u64,
+}
+
+pub fn record(
+    root: &Root,
+    actor: u64,
+    action: &str,
+    details: String,
+) -> Result<(), VersionedError> {
+    let record = AuditRecord {
+        actor,
+        action: action.to_owned(),
+        details,
+        timestamp: unix_now(),
+    };
+    // time first keeps records sorted, random part keeps simultaneous ones apart
+    let key = format!(
+        "{}{:016x}{:016x}",
+        AUDIT_PREFIX,
+        record.timestamp,
+        rand::random::<u64>()
+    );
+    let bytes = serde_json::to_vec(&record).map_err(VersionedError::Json)?;
+    root.tree()
+        .insert(key, bytes)
+        .map_err(VersionedError::Sled)?;
+    Ok(())
+}
+
+/// Up to `limit` latest records, newest first
+pub fn recent(root: &Root, limit: usize) -> Result<Vec<AuditRecord>, VersionedError> {
+    root.tree()
+        .scan_prefix(AUDIT_PREFIX)
+        .rev()
+        .take(limit)
+        .map(|pair| {
+            let (_key, value) = pair.map_err(VersionedError::Sled)?;
+            serde_json::from_slice(&value).map_err(VersionedError::Json)
+        })
+        .collect()
+}
@@ -1,27 +1,73 @@
+use std::borrow::Cow;
+
 use super::{
-    tree::{Bark, Leaf, Trunk},
+    tools::slice_to_u64,
+    tree::{Bark, Leaf, LeafInfo, Root, Trunk},
     versioned::VersionedError,
     ArcSlice,
 };
 
+pub(super) const SLOT_PREFIX: &str = "image_";
+pub(super) const COUNTER_SUFFIX: &str = "_ver";
+
 //pub type CharTrunk<'a> = Trunk<'a, CharTrun>;
+/// Character images of one slot. Avatar keeps the original branch names,
+/// other slots have their own counter, secrets and timestamps prefixed with `image_<slot>`.
+#[derive(Clone)]
 pub struct CharTrunk {
-    image_branch: &'static str,
+    image_branch: Cow<'static, str>,
+    secret: Cow<'static, str>,
+    counter: Cow<'static, str>,
+    timestamp: Cow<'static, str>,
 }
 impl Default for CharTrunk {
     fn default() -> Self {
         CharTrunk {
-            image_branch: "avatar",
+            image_branch: "avatar".into(),
+            secret: "secret".into(),
+            counter: "ver".into(),
+            timestamp: "time".into(),
         }
     }
 }
+impl CharTrunk {
+    pub fn slot(name: &str) -> Self {
+        let branch = format!("{}{}", SLOT_PREFIX, name);
+        CharTrunk {
+            secret: format!("{}_secret", branch).into(),
+            counter: format!("{}{}", branch, COUNTER_SUFFIX).into(),
+            timestamp: format!("{}_time", branch).into(),
+            image_branch: branch.into(),
+        }
+    }
+
+    pub fn image_branch(&self) -> &str {
+        &self.image_branch
+    }
+
+    /// Versioned branch with scaled copies of images
+    pub fn size_branch(&self, size: u32) -> String {
+        format!("{}@{}", self.image_branch, size)
+    }
+
+    /// Versioned branch with moderation status of every image version
+    pub fn status_branch(&self) -> String {
+        format!("{}_status", self.image_branch)
+    }
+}
 impl Bark for CharTrunk {
+    type Id = u32;
+
     fn secret(&self) -> &str {
-        "secret"
+        &self.secret
     }
 
     fn counter(&self) -> &str {
-        "ver"
+        &self.counter
+    }
+
+    fn timestamp(&self) -> &str {
+        &self.timestamp
     }
 
     fn trunk(&self) -> &str {
@@ -31,10 +77,51 @@ impl Bark for CharTrunk {
 
 impl<'a> Trunk<'a, CharTrunk> {
     pub fn get_image(&self, input_key: Option<u32>) -> Result<Leaf<ArcSlice>, VersionedError> {
-        self.get_versioned(self.bark().image_branch, input_key)
+        self.get_versioned(self.bark().image_branch(), input_key)
+    }
+
+    pub fn image_history(&self) -> Result<Vec<LeafInfo>, VersionedError> {
+        self.history(self.bark().image_branch())
     }
 
     pub fn set_image(&self, data: Vec<u8>) -> Result<Leaf<()>, VersionedError> {
-        self.set_versioned(self.bark().image_branch, data)
+        self.set_versioned(self.bark().image_branch(), data)
+    }
+
+    /// Unix timestamp of the upload, missing for old versions
+    pub fn get_image_time(&self, ver: u32) -> Result<Option<u64>, VersionedError> {
+        Ok(self
+            .get_version(self.bark().timestamp(), ver)?
+            .and_then(|bytes| slice_to_u64(&bytes)))
+    }
+
+    pub fn get_image_size(&self, ver: u32, size: u32) -> Result<Option<ArcSlice>, VersionedError> {
+        self.get_version(&self.bark().size_branch(size), ver)
+    }
+
+    pub fn set_image_size(&self, ver: u32, size: u32, data: Vec<u8>) -> Result<(), VersionedError> {
+        self.set_version(&self.bark().size_branch(size), ver, data)
+    }
+}
+
+/// Scans `char/*/<image_branch>/*` keys for characters that have at least one image
+pub fn image_characters(root: &Root) -> Result<Vec<u32>, VersionedError> {
+    let bark = CharTrunk::default();
+    let prefix = format!("{}/", bark.trunk());
+    let infix = format!("/{}/", bark.image_branch());
+
+    let mut chars: Vec<u32> = vec![];
+    for pair in root.tree().scan_prefix(&prefix) {
+        let (key, _value) = pair.map_err(VersionedError::Sled)?;
+        let id = std::str::from_utf8(&key[prefix.len()..])
+            .ok()
+            .and_then(|rest| rest.split_once(infix.as_str()))
+            .and_then(|(id, _ver)| u32::from_str_radix(id, 16).ok());
+        match id {
+            // keys are sorted, so versions of the same character go in a row
+            Some(id) if chars.last() != Some(&id) => chars.push(id),
+            _ => {}
+        }
     }
+    Ok(chars)
 }
@@ -0,0 +1,281 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use serde::Serialize;
+
+use super::{
+    audit::AUDIT_PREFIX,
+    character::{COUNTER_SUFFIX, SLOT_PREFIX},
+    ownership::{
+        AUTHKEY_BRANCH, AUTHKEY_ISSUED_BRANCH, AUTHKEY_LEN, LOG_BRANCH, LOG_COUNTER, OWNER_BRANCH,
+    },
+    session::{SESSION_PREFIX, USER_INDEX_PREFIX},
+    token::TOKEN_PREFIX,
+    tree::Bark,
+    CharTrunk, Root, UserTrunk, VersionedError,
+};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum ProblemKind {
+    /// Key doesn't follow `trunk/ID/branch[/VER]` layout, where ID is at least 8 hex digits,
+    /// user ids are padded to 16
+    Layout,
+    /// Version suffix isn't 8 hex digits
+    Version,
+    CounterLength(usize),
+    MissingSecret,
+    OwnerLength(usize),
+    AuthkeyLength(usize),
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProblemKind::Layout => write!(f, "unexpected key layout"),
+            ProblemKind::Version => write!(f, "version suffix isn't hex"),
+            ProblemKind::CounterLength(len) => write!(f, "counter with length of {}", len),
+            ProblemKind::MissingSecret => write!(f, "leaf without matching secret"),
+            ProblemKind::OwnerLength(len) => write!(f, "owner_id with length of {}", len),
+            ProblemKind::AuthkeyLength(len) => write!(f, "authkey with length of {}", len),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub key: String,
+    pub kind: ProblemKind,
+    pub description: String,
+    pub repaired: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub scanned: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn repaired(&self) -> usize {
+        self.problems
+            .iter()
+            .filter(|problem| problem.repaired)
+            .count()
+    }
+
+    fn push(&mut self, key: String, kind: ProblemKind) {
+        self.problems.push(Problem {
+            key,
+            kind,
+            description: kind.to_string(),
+            repaired: false,
+        });
+    }
+}
+
+enum Fix {
+    Remove(Vec<String>),
+    Insert(String, Vec<u8>),
+}
+
+/// Walks the whole tree and reports broken entries, fixes them if `repair` is set.
+pub fn check(root: &Root, repair: bool) -> Result<Report, VersionedError> {
+    let bark = CharTrunk::default();
+    let user = UserTrunk::default();
+    let user_id_len = user.format_id(0).len();
+    let mut report = Report::default();
+    let mut fixes: Vec<Option<Fix>> = vec![];
+    // (trunk, id) -> branch -> versions
+    let mut versions: BTreeMap<(String, u64), BTreeMap<String, BTreeSet<u32>>> = BTreeMap::new();
+    let mut bad_counters = vec![];
+    // (id, image branch, secret branch) of every image slot that has a counter
+    let mut slots: BTreeSet<(u64, String, String)> = BTreeSet::new();
+
+    for pair in root.tree().iter() {
+        let (full_key, value) = pair.map_err(VersionedError::Sled)?;
+        report.scanned += 1;
+
+        let key = match std::str::from_utf8(&full_key) {
+            Ok(key) => key,
+            Err(_) => {
+                report.push(
+                    String::from_utf8_lossy(&full_key).into(),
+                    ProblemKind::Layout,
+                );
+                fixes.push(None);
+                continue;
+            }
+        };
+        // tokens and sessions are stored by hash of the secret, audit log by time,
+        // none of them is versioned
+        if key.starts_with(TOKEN_PREFIX)
+            || key.starts_with(SESSION_PREFIX)
+            || key.starts_with(USER_INDEX_PREFIX)
+            || key.starts_with(AUDIT_PREFIX)
+        {
+            continue;
+        }
+        let mut parts = key.splitn(4, '/');
+        let (trunk, id, branch, ver) = (parts.next(), parts.next(), parts.next(), parts.next());
+        let (trunk, id, branch) = match (trunk, id, branch) {
+            (Some(trunk), Some(id), Some(branch))
+                if id.len() >= 8 && (trunk != user.trunk() || id.len() == user_id_len) =>
+            {
+                match u64::from_str_radix(id, 16) {
+                    Ok(id) => (trunk, id, branch),
+                    Err(_) => {
+                        report.push(key.into(), ProblemKind::Layout);
+                        fixes.push(None);
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                report.push(key.into(), ProblemKind::Layout);
+                fixes.push(None);
+                continue;
+            }
+        };
+
+        if let Some(ver) = ver {
+            match u32::from_str_radix(ver, 16) {
+                Ok(ver_num) if ver.len() == 8 => {
+                    versions
+                        .entry((trunk.into(), id))
+                        .or_default()
+                        .entry(branch.into())
+                        .or_default()
+                        .insert(ver_num);
+                }
+                _ => {
+                    report.push(key.into(), ProblemKind::Version);
+                    fixes.push(Some(Fix::Remove(vec![key.into()])));
+                }
+            }
+            continue;
+        }
+
+        if trunk != bark.trunk() {
+            continue;
+        }
+        if branch == bark.counter() || branch.ends_with(COUNTER_SUFFIX) {
+            if let Some(slot) = counter_slot(&bark, branch) {
+                slots.insert((id, slot.image_branch().to_owned(), slot.secret().to_owned()));
+            }
+            if value.len() != 4 {
+                report.push(key.into(), ProblemKind::CounterLength(value.len()));
+                fixes.push(None);
+                bad_counters.push((report.problems.len() - 1, id, branch.to_owned()));
+            }
+        } else if branch == OWNER_BRANCH {
+            if value.len() != 8 {
+                report.push(key.into(), ProblemKind::OwnerLength(value.len()));
+                fixes.push(Some(Fix::Remove(vec![key.into()])));
+            }
+        } else if branch == AUTHKEY_BRANCH && value.len() != AUTHKEY_LEN {
+            report.push(key.into(), ProblemKind::AuthkeyLength(value.len()));
+            fixes.push(Some(Fix::Remove(vec![
+                key.into(),
+                format!("{}/{:08X}/{}", trunk, id, AUTHKEY_ISSUED_BRANCH),
+            ])));
+        }
+    }
+
+    // leaves and secrets are written together, so every leaf should have one
+    for (trunk, id) in versions.keys() {
+        // avatars are checked even if their counter is lost
+        if trunk == bark.trunk() {
+            slots.insert((
+                *id,
+                bark.image_branch().to_owned(),
+                bark.secret().to_owned(),
+            ));
+        }
+    }
+    let empty = BTreeSet::new();
+    for (id, image, secret) in &slots {
+        let branches = match versions.get(&(bark.trunk().to_owned(), *id)) {
+            Some(branches) => branches,
+            None => continue,
+        };
+        let trunk = bark.trunk();
+        // images written before secrets were introduced have none at all
+        let secrets = match branches.get(secret) {
+            Some(secrets) => secrets,
+            None => continue,
+        };
+        let leaves = branches.get(image).unwrap_or(&empty);
+        for ver in leaves.difference(secrets) {
+            report.push(
+                format!("{}/{:08X}/{}/{:08X}", trunk, id, image, ver),
+                ProblemKind::MissingSecret,
+            );
+            // a made up secret is known neither to the game nor to any link
+            fixes.push(None);
+        }
+    }
+
+    // broken counters are restored from the newest version they have produced
+    for (index, id, counter) in bad_counters {
+        let counted: Vec<String> = match counter_slot(&bark, &counter) {
+            Some(slot) => vec![
+                slot.image_branch().to_owned(),
+                slot.secret().to_owned(),
+                slot.timestamp().to_owned(),
+                slot.status_branch(),
+            ],
+            None if counter == LOG_COUNTER => vec![LOG_BRANCH.to_owned()],
+            None => vec![],
+        };
+        let last = versions
+            .get(&(bark.trunk().to_owned(), id))
+            .and_then(|branches| {
+                counted
+                    .iter()
+                    .filter_map(|branch| branches.get(branch))
+                    .filter_map(|vers| vers.iter().next_back())
+                    .max()
+                    .copied()
+            });
+        let key = report.problems[index].key.clone();
+        fixes[index] = Some(match last {
+            Some(last) => Fix::Insert(key, last.to_be_bytes().to_vec()),
+            None => Fix::Remove(vec![key]),
+        });
+    }
+
+    if repair {
+        for (problem, fix) in report.problems.iter_mut().zip(fixes) {
+            match fix {
+                Some(Fix::Remove(keys)) => {
+                    for key in keys {
+                        root.tree().remove(key).map_err(VersionedError::Sled)?;
+                    }
+                }
+                Some(Fix::Insert(key, value)) => {
+                    root.tree()
+                        .insert(key, value)
+                        .map_err(VersionedError::Sled)?;
+                }
+                None => continue,
+            }
+            problem.repaired = true;
+        }
+        root.tree().flush().map_err(VersionedError::Sled)?;
+    }
+
+    Ok(report)
+}
+
+/// Image slot versioned by the counter, avatar uses `ver` and other slots `image_<slot>_ver`
+fn counter_slot(bark: &CharTrunk, counter: &str) -> Option<CharTrunk> {
+    if counter == bark.counter() {
+        return Some(CharTrunk::default());
+    }
+    let name = counter
+        .strip_prefix(SLOT_PREFIX)?
+        .strip_suffix(COUNTER_SUFFIX)?;
+    Some(CharTrunk::slot(name))
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    tools::{slice_to_u32, unix_now},
+    tree::{Bark, Trunk},
+    ArcSlice, CharTrunk, Leaf, Root, VersionedError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModerationState {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+/// Moderation status of single image version, versions without one were uploaded
+/// before moderation was enabled and are treated as approved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageStatus {
+    pub state: ModerationState,
+    pub moderator: Option<u64>,
+    pub reason: Option<String>,
+    pub timestamp: u64,
+}
+
+impl ImageStatus {
+    pub fn pending() -> Self {
+        ImageStatus {
+            state: ModerationState::Pending,
+            moderator: None,
+            reason: None,
+            timestamp: unix_now(),
+        }
+    }
+
+    fn is_published(status: Option<&Self>) -> bool {
+        status.map_or(true, |status| status.state == ModerationState::Approved)
+    }
+}
+
+fn parse_status(bytes: &[u8]) -> Result<ImageStatus, VersionedError> {
+    serde_json::from_slice(bytes).map_err(VersionedError::Json)
+}
+
+impl<'a> Trunk<'a, CharTrunk> {
+    pub fn get_image_status(&self, ver: u32) -> Result<Option<ImageStatus>, VersionedError> {
+        self.get_version(&self.bark().status_branch(), ver)?
+            .map(|bytes| parse_status(&bytes))
+            .transpose()
+    }
+
+    pub fn set_image_status(&self, ver: u32, status: &ImageStatus) -> Result<(), VersionedError> {
+        let bytes = serde_json::to_vec(status).map_err(VersionedError::Json)?;
+        self.set_version(&self.bark().status_branch(), ver, bytes)
+    }
+
+    /// Stores new image version waiting for moderation
+    pub fn set_pending_image(&self, data: Vec<u8>) -> Result<Leaf<()>, VersionedError> {
+        let status = serde_json::to_vec(&ImageStatus::pending()).map_err(VersionedError::Json)?;
+        self.set_versioned_with(
+            self.bark().image_branch(),
+            data,
+            (&self.bark().status_branch(), status),
+        )
+    }
+
+    /// Records moderator's decision about pending version, decisions are final because
+    /// the game isn't told when a live version gets rejected
+    pub fn moderate_image(
+        &self,
+        ver: u32,
+        state: ModerationState,
+        moderator: u64,
+        reason: Option<String>,
+    ) -> Result<(), VersionedError> {
+        let mut status = self
+            .get_image_status(ver)?
+            .ok_or(VersionedError::NotFound)?;
+        if status.state != ModerationState::Pending {
+            return Err(VersionedError::UnexpectedOldValue);
+        }
+        status.state = state;
+        status.moderator = Some(moderator);
+        status.reason = reason;
+        status.timestamp = unix_now();
+        self.set_image_status(ver, &status)
+    }
+
+    /// Statuses of image versions, oldest first
+    pub fn image_statuses(&self) -> Result<Vec<(u32, ImageStatus)>, VersionedError> {
+        self.list(&self.bark().status_branch())?
+            .into_iter()
+            .map(|(ver, bytes)| Ok((ver, parse_status(&bytes)?)))
+            .collect()
+    }
+
+    /// Newest approved version and its secret, `input_key` should match that secret
+    pub fn published_version(
+        &self,
+        input_key: Option<u32>,
+    ) -> Result<(u32, Option<u32>), VersionedError> {
+        let statuses: BTreeMap<u32, ImageStatus> = self.image_statuses()?.into_iter().collect();
+        let secrets = self.list_secrets()?;
+        if secrets.is_empty() {
+            // images written before secrets were introduced
+            return self.get_image(input_key).map(|leaf| (leaf.ver, None));
+        }
+        let (ver, secret) = secrets
+            .into_iter()
+            .rev()
+            .find(|(ver, _secret)| ImageStatus::is_published(statuses.get(ver)))
+            .ok_or(VersionedError::NotFound)?;
+        if input_key.map_or(false, |input_key| input_key != secret) {
+            return Err(VersionedError::AccessDenied);
+        }
+        Ok((ver, Some(secret)))
+    }
+
+    /// Stores copy of published version as the newest one, with new secret
+    pub fn restore_image(&self, ver: u32) -> Result<Leaf<()>, VersionedError> {
+        let status = self.get_image_status(ver)?;
+        if !ImageStatus::is_published(status.as_ref()) {
+            return Err(VersionedError::AccessDenied);
+        }
+        let data = self
+            .get_image_version(ver)?
+            .ok_or(VersionedError::NotFound)?;
+        self.set_image(data.to_vec())
+    }
+
+    /// Image of exact version regardless of its status, for moderators
+    pub fn get_image_version(&self, ver: u32) -> Result<Option<ArcSlice>, VersionedError> {
+        self.get_version(self.bark().image_branch(), ver)
+    }
+
+    /// Secret of exact version, to publish it after approval
+    pub fn get_image_secret(&self, ver: u32) -> Result<Option<u32>, VersionedError> {
+        Ok(self
+            .get_version(self.bark().secret(), ver)?
+            .and_then(|bytes| slice_to_u32(&bytes)))
+    }
+
+    /// Whether there is approved version newer than `ver`
+    pub fn has_newer_published(&self, ver: u32) -> Result<bool, VersionedError> {
+        let statuses: BTreeMap<u32, ImageStatus> = self.image_statuses()?.into_iter().collect();
+        Ok(self
+            .list_secrets()?
+            .into_iter()
+            .any(|(other, _)| other > ver && ImageStatus::is_published(statuses.get(&other))))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingImage {
+    pub char_id: u32,
+    /// `None` for avatars
+    pub slot: Option<String>,
+    pub ver: u32,
+    pub status: ImageStatus,
+}
+
+/// Scans all characters for avatar and slot image versions waiting for moderation,
+/// oldest uploads first
+pub fn pending_images(root: &Root, slots: &[&str]) -> Result<Vec<PendingImage>, VersionedError> {
+    let prefix = format!("{}/", CharTrunk::default().trunk());
+    let infixes: Vec<(Option<&str>, String)> = std::iter::once((None, CharTrunk::default()))
+        .chain(
+            slots
+                .iter()
+                .map(|slot| (Some(*slot), CharTrunk::slot(slot))),
+        )
+        .map(|(slot, bark)| (slot, format!("/{}/", bark.status_branch())))
+        .collect();
+
+    let mut pending = vec![];
+    for pair in root.tree().scan_prefix(&prefix) {
+        let (key, value) = pair.map_err(VersionedError::Sled)?;
+        let key = match std::str::from_utf8(&key) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        let found = infixes.iter().find_map(|(slot, infix)| {
+            key[prefix.len()..]
+                .split_once(infix.as_str())
+                .map(|parts| (slot, parts))
+        });
+        let (slot, (id, ver)) = match found {
+            Some(found) => found,
+            None => continue,
+        };
+        let (char_id, ver) = match (u32::from_str_radix(id, 16), u32::from_str_radix(ver, 16)) {
+            (Ok(char_id), Ok(ver)) => (char_id, ver),
+            _ => continue,
+        };
+        let status = parse_status(&value)?;
+        if status.state == ModerationState::Pending {
+            pending.push(PendingImage {
+                char_id,
+                slot: slot.map(String::from),
+                ver,
+                status,
+            });
+        }
+    }
+    pending.sort_by_key(|image| image.status.timestamp);
+    Ok(pending)
+}
@@ -1,9 +1,54 @@
-use super::{tools::slice_to_u64, CharTrunk, Root, VersionedError};
+use std::{
+    convert::{TryFrom, TryInto},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    tools::{constant_time_eq, slice_to_u32, slice_to_u64, unix_now},
+    tree::{Bark, Trunk},
+    CharTrunk, Root, UserTrunk, VersionedError,
+};
+
+pub(super) const OWNER_BRANCH: &str = "owner_id";
+const LOCK_BRANCH: &str = "owner_lock";
+pub(super) const AUTHKEY_BRANCH: &str = "authkey";
+pub(super) const AUTHKEY_ISSUED_BRANCH: &str = "authkey_issued";
+const CLAIM_FAILURES_BRANCH: &str = "claim_failures";
+pub(super) const LOG_BRANCH: &str = "owner_log";
+pub(super) const LOG_COUNTER: &str = "owner_log_ver";
+
+pub const AUTHKEY_LEN: usize = 12;
+/// Discord ids never have the highest bit set, so it marks local accounts
+const LOCAL_USER_FLAG: u64 = 1 << 63;
+
+/// Id of the local account bound to the character
+pub fn local_user_id(char_id: u32) -> u64 {
+    LOCAL_USER_FLAG | char_id as u64
+}
+
+/// Character of the local account, `None` for Discord users
+pub fn local_char_id(user_id: u64) -> Option<u32> {
+    if user_id & LOCAL_USER_FLAG == 0 {
+        return None;
+    }
+    u32::try_from(user_id & !LOCAL_USER_FLAG).ok()
+}
+
+pub fn new_authkey() -> [u8; AUTHKEY_LEN] {
+    loop {
+        let random = rand::random();
+        if random != [0u8; AUTHKEY_LEN] {
+            break random;
+        }
+    }
+}
 
 pub fn get_ownership(root: &Root, char_id: u32) -> Result<Option<u64>, VersionedError> {
     let result = root
         .trunk(char_id, None, CharTrunk::default())
-        .get_bare_branch("owner_id");
+        .get_bare_branch(OWNER_BRANCH);
     match result {
         Ok(owner) => {
             let user_id = slice_to_u64(&owner);
@@ -18,27 +63,353 @@ pub fn get_ownership(root: &Root, char_id: u32) -> Result<Option<u64>, Versioned
 }
 
 pub fn set_ownership(root: &Root, char_id: u32, user_id: u64) -> Result<(), VersionedError> {
+    replace_ownership(root, char_id, user_id, None)
+}
+
+/// Sets the owner of unowned character, or of one owned by `replaced`
+fn replace_ownership(
+    root: &Root,
+    char_id: u32,
+    user_id: u64,
+    replaced: Option<u64>,
+) -> Result<(), VersionedError> {
+    if is_locked(root, char_id)? {
+        return Err(VersionedError::AccessDenied);
+    }
     let new_owner = user_id.to_be_bytes();
+    let replaced_owner = replaced.map(u64::to_be_bytes);
     let result = root
         .trunk(char_id, None, CharTrunk::default())
-        .get_bare_branch_or_default("owner_id", &new_owner, |_| true);
+        .get_bare_branch_or_default(OWNER_BRANCH, &new_owner, |owner| {
+            replaced_owner.map_or(true, |replaced| owner != replaced)
+        });
     match result {
         // aleady same owner
-        Ok(Some(owner)) if &*owner == &new_owner => Ok(()),
+        Ok(Some(owner)) if *owner == new_owner => Ok(()),
         // successfully setted
-        Ok(None) => Ok(()),
+        Ok(None) => {
+            if let Some(replaced) = replaced {
+                index_ownership(root, char_id, replaced, false)?;
+            }
+            index_ownership(root, char_id, user_id, true)
+        }
         Err(err) => Err(err),
         _ => Err(VersionedError::AccessDenied),
     }
 }
 
+/// Characters owned by the user, from the index kept in `UserTrunk`
+pub fn owned_characters(root: &Root, user_id: u64) -> Result<Vec<u32>, VersionedError> {
+    let trunk = root.trunk(user_id, None, UserTrunk::default());
+    match trunk.get_characters()? {
+        Some(chars) => Ok(chars),
+        None => {
+            // owners from before the index existed
+            let chars = scan_owned_characters(root, user_id)?;
+            trunk.set_characters(&chars)?;
+            Ok(chars)
+        }
+    }
+}
+
+/// Keeps the index of the user in sync after the owner of the character was changed
+fn index_ownership(
+    root: &Root,
+    char_id: u32,
+    user_id: u64,
+    owned: bool,
+) -> Result<(), VersionedError> {
+    let trunk = root.trunk(user_id, None, UserTrunk::default());
+    if trunk.get_characters()?.is_none() {
+        // the scan already sees the change
+        let chars = scan_owned_characters(root, user_id)?;
+        return trunk.set_characters(&chars);
+    }
+    trunk.update_characters(char_id, owned)
+}
+
+/// Scans all characters for ones owned by the user
+fn scan_owned_characters(root: &Root, user_id: u64) -> Result<Vec<u32>, VersionedError> {
+    let bark = CharTrunk::default();
+    let prefix = format!("{}/", bark.trunk());
+    let suffix = format!("/{}", OWNER_BRANCH);
+    let owner = user_id.to_be_bytes();
+
+    let mut chars = vec![];
+    for pair in root.tree().scan_prefix(&prefix) {
+        let (key, value) = pair.map_err(VersionedError::Sled)?;
+        if !key.ends_with(suffix.as_bytes()) || *value != owner[..] {
+            continue;
+        }
+        let id = key
+            .get(prefix.len()..key.len() - suffix.len())
+            .and_then(|id| std::str::from_utf8(id).ok())
+            .and_then(|id| u32::from_str_radix(id, 16).ok());
+        if let Some(id) = id {
+            chars.push(id);
+        }
+    }
+    Ok(chars)
+}
+
 pub fn get_auth(root: &Root, char_id: u32) -> Result<Option<sled::IVec>, VersionedError> {
     let result = root
         .trunk(char_id, None, CharTrunk::default())
-        .get_bare_branch("authkey");
+        .get_bare_branch(AUTHKEY_BRANCH);
     match result {
         Ok(authkey) => Ok(Some(authkey)),
         Err(VersionedError::NotFound) => Ok(None),
         Err(err) => Err(err),
     }
 }
+
+// ===== Authkeys =====
+
+/// Returns authkey that should be sent to the game for unowned character,
+/// or for character owned by its local account, which logs in with the key.
+/// Existing key is reused unless it is expired or `rotate` is set.
+pub fn issue_authkey(
+    root: &Root,
+    char_id: u32,
+    ttl: Option<Duration>,
+    rotate: bool,
+) -> Result<Option<[u8; AUTHKEY_LEN]>, VersionedError> {
+    match get_ownership(root, char_id)? {
+        Some(owner) if owner != local_user_id(char_id) => return Ok(None),
+        _ => {}
+    }
+    let trunk = root.trunk(char_id, None, CharTrunk::default());
+    if !rotate {
+        if let Some(authkey) = valid_authkey(&trunk, ttl)? {
+            return Ok(Some(authkey));
+        }
+    }
+    rotate_authkey(&trunk).map(Some)
+}
+
+/// Used key can't be used again, the game gets a new one with the next request
+fn consume_authkey(trunk: &Trunk<CharTrunk>) -> Result<(), VersionedError> {
+    trunk.remove_bare_branch(AUTHKEY_BRANCH)?;
+    trunk.remove_bare_branch(AUTHKEY_ISSUED_BRANCH)?;
+    Ok(())
+}
+
+fn rotate_authkey(trunk: &Trunk<CharTrunk>) -> Result<[u8; AUTHKEY_LEN], VersionedError> {
+    let authkey = new_authkey();
+    trunk.set_bare_branch(AUTHKEY_ISSUED_BRANCH, &unix_now().to_be_bytes())?;
+    trunk.set_bare_branch(AUTHKEY_BRANCH, &authkey[..])?;
+    Ok(authkey)
+}
+
+fn valid_authkey(
+    trunk: &Trunk<CharTrunk>,
+    ttl: Option<Duration>,
+) -> Result<Option<[u8; AUTHKEY_LEN]>, VersionedError> {
+    let authkey = match trunk.get_bare_branch(AUTHKEY_BRANCH) {
+        Ok(authkey) => authkey,
+        Err(VersionedError::NotFound) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let authkey: [u8; AUTHKEY_LEN] = match authkey.as_ref().try_into() {
+        Ok(authkey) => authkey,
+        Err(_) => return Ok(None),
+    };
+    if let Some(ttl) = ttl {
+        // keys issued before timestamps were recorded are treated as expired
+        let issued = match trunk.get_bare_branch(AUTHKEY_ISSUED_BRANCH) {
+            Ok(issued) => slice_to_u64(&issued),
+            Err(VersionedError::NotFound) => None,
+            Err(err) => return Err(err),
+        };
+        match issued {
+            Some(issued) if unix_now().saturating_sub(issued) < ttl.as_secs() => {}
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some(authkey))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClaimLimits {
+    pub ttl: Option<Duration>,
+    pub max_failures: u32,
+    pub window: Duration,
+}
+
+/// Binds unowned character to the user if `received` matches the stored authkey.
+/// Discord user also takes over the character from its local account this way.
+/// The authkey is consumed on success, failed attempts are counted per character.
+pub fn claim_ownership(
+    root: &Root,
+    char_id: u32,
+    user_id: u64,
+    received: &[u8],
+    limits: ClaimLimits,
+) -> Result<(), VersionedError> {
+    let trunk = root.trunk(char_id, None, CharTrunk::default());
+    check_authkey(&trunk, received, limits)?;
+
+    let replaced = Some(local_user_id(char_id)).filter(|_| local_char_id(user_id).is_none());
+    replace_ownership(root, char_id, user_id, replaced)?;
+    consume_authkey(&trunk)
+}
+
+/// Logs into the local account of the character, unowned character is claimed by it.
+/// The key is consumed by login, so a leaked key can't be reused.
+pub fn authkey_login(
+    root: &Root,
+    char_id: u32,
+    received: &[u8],
+    limits: ClaimLimits,
+) -> Result<u64, VersionedError> {
+    let user_id = local_user_id(char_id);
+    match get_ownership(root, char_id)? {
+        None => claim_ownership(root, char_id, user_id, received, limits)?,
+        Some(owner) if owner == user_id => {
+            let trunk = root.trunk(char_id, None, CharTrunk::default());
+            check_authkey(&trunk, received, limits)?;
+            consume_authkey(&trunk)?;
+        }
+        // linked to Discord account, it should be used instead
+        Some(_) => return Err(VersionedError::AccessDenied),
+    }
+    Ok(user_id)
+}
+
+/// Compares `received` with the stored authkey, failed attempts are throttled
+fn check_authkey(
+    trunk: &Trunk<CharTrunk>,
+    received: &[u8],
+    limits: ClaimLimits,
+) -> Result<(), VersionedError> {
+    let now = unix_now();
+
+    let failures = match trunk.get_bare_branch(CLAIM_FAILURES_BRANCH) {
+        Ok(failures) => parse_failures(&failures),
+        Err(VersionedError::NotFound) => None,
+        Err(err) => return Err(err),
+    };
+    if let Some((count, last)) = failures {
+        if count >= limits.max_failures && now.saturating_sub(last) < limits.window.as_secs() {
+            return Err(VersionedError::TooManyAttempts);
+        }
+    }
+
+    match valid_authkey(trunk, limits.ttl)? {
+        Some(stored) if constant_time_eq(&stored, received) => {}
+        _ => {
+            let window = limits.window.as_secs();
+            trunk.update_bare_branch(CLAIM_FAILURES_BRANCH, |old| {
+                let count = match old.and_then(parse_failures) {
+                    Some((count, last)) if now.saturating_sub(last) < window => count + 1,
+                    _ => 1,
+                };
+                let mut bytes = count.to_be_bytes().to_vec();
+                bytes.extend_from_slice(&now.to_be_bytes());
+                Some(bytes)
+            })?;
+            return Err(VersionedError::AccessDenied);
+        }
+    }
+    trunk.remove_bare_branch(CLAIM_FAILURES_BRANCH)?;
+    Ok(())
+}
+
+// failed attempts are stored as u32 count followed by u64 timestamp of the last one
+fn parse_failures(bytes: &[u8]) -> Option<(u32, u64)> {
+    if bytes.len() != 12 {
+        return None;
+    }
+    Some((slice_to_u32(&bytes[..4])?, slice_to_u64(&bytes[4..])?))
+}
+
+pub fn is_locked(root: &Root, char_id: u32) -> Result<bool, VersionedError> {
+    let result = root
+        .trunk(char_id, None, CharTrunk::default())
+        .get_bare_branch(LOCK_BRANCH);
+    match result {
+        Ok(_) => Ok(true),
+        Err(VersionedError::NotFound) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+// ===== Admin changes =====
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OwnershipAction {
+    Release,
+    Reassign(u64),
+    Lock,
+    Unlock,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnershipRecord {
+    pub action: OwnershipAction,
+    pub old_owner: Option<u64>,
+    pub admin_id: u64,
+    pub reason: String,
+    pub timestamp: u64,
+}
+
+/// Applies an admin decision regardless of the current owner and appends it to the ownership log.
+/// Releasing also regenerates the authkey, so a key sent to the game before can't be used to claim.
+pub fn change_ownership(
+    root: &Root,
+    char_id: u32,
+    action: OwnershipAction,
+    admin_id: u64,
+    reason: String,
+) -> Result<OwnershipRecord, VersionedError> {
+    let old_owner = get_ownership(root, char_id)?;
+    let trunk = root.trunk(char_id, None, CharTrunk::default());
+    match action {
+        OwnershipAction::Release => {
+            trunk.remove_bare_branch(OWNER_BRANCH)?;
+            rotate_authkey(&trunk)?;
+            if let Some(old_owner) = old_owner {
+                index_ownership(root, char_id, old_owner, false)?;
+            }
+        }
+        OwnershipAction::Reassign(user_id) => {
+            trunk.set_bare_branch(OWNER_BRANCH, &user_id.to_be_bytes())?;
+            if let Some(old_owner) = old_owner.filter(|old_owner| *old_owner != user_id) {
+                index_ownership(root, char_id, old_owner, false)?;
+            }
+            index_ownership(root, char_id, user_id, true)?;
+        }
+        OwnershipAction::Lock => {
+            trunk.set_bare_branch(LOCK_BRANCH, &[1u8])?;
+        }
+        OwnershipAction::Unlock => {
+            trunk.remove_bare_branch(LOCK_BRANCH)?;
+        }
+    }
+
+    let record = OwnershipRecord {
+        action,
+        old_owner,
+        admin_id,
+        reason,
+        timestamp: unix_now(),
+    };
+    let bytes = serde_json::to_vec(&record).map_err(VersionedError::Json)?;
+    trunk.append(LOG_COUNTER, LOG_BRANCH, bytes)?;
+    Ok(record)
+}
+
+pub fn last_ownership_change(
+    root: &Root,
+    char_id: u32,
+) -> Result<Option<OwnershipRecord>, VersionedError> {
+    let last = root
+        .trunk(char_id, None, CharTrunk::default())
+        .get_latest(LOG_BRANCH)?;
+    match last {
+        Some((_ver, bytes)) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(VersionedError::Json),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,251 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    tools::{new_secret, sha256_hex, unix_now},
+    Root, VersionedError,
+};
+
+pub(super) const SESSION_PREFIX: &str = "session/";
+/// Index of logged in sessions: `session_user/{user_id:016X}/{id}`, value is empty.
+/// Entries can outlive their sessions, readers check the session itself.
+pub(super) const USER_INDEX_PREFIX: &str = "session_user/";
+/// `last_seen` and `expires` are refreshed on load not more often than this,
+/// to avoid a write per request
+const TOUCH_INTERVAL: u64 = 60;
+/// Sessions without a logged in user only keep login state, they don't need the full ttl
+const ANONYMOUS_TTL: u64 = 24 * 60 * 60;
+
+/// Server side session, stored by hash of the key from the cookie
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub user_id: Option<u64>,
+    pub state: HashMap<String, String>,
+    pub created: u64,
+    pub last_seen: u64,
+    pub expires: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    /// Hex of the key's hash, safe to show and to use for revocation
+    pub id: String,
+    pub created: u64,
+    pub last_seen: u64,
+    pub expires: u64,
+}
+
+pub fn new_session_key() -> String {
+    new_secret("")
+}
+
+fn session_key(id: &str) -> String {
+    format!("{}{}", SESSION_PREFIX, id)
+}
+
+fn user_index_prefix(user_id: u64) -> String {
+    format!("{}{:016X}/", USER_INDEX_PREFIX, user_id)
+}
+
+fn user_index_key(user_id: u64, id: &str) -> String {
+    format!("{}{}", user_index_prefix(user_id), id)
+}
+
+fn parse_session(bytes: &[u8]) -> Result<SessionRecord, VersionedError> {
+    serde_json::from_slice(bytes).map_err(VersionedError::Json)
+}
+
+fn expires(user_id: Option<u64>, now: u64, ttl: Duration) -> u64 {
+    let ttl = match user_id {
+        Some(_) => ttl.as_secs(),
+        None => ttl.as_secs().min(ANONYMOUS_TTL),
+    };
+    now + ttl
+}
+
+/// Removes the session and its index entry
+fn remove_record(root: &Root, id: &str) -> Result<(), VersionedError> {
+    let old = root
+        .tree()
+        .remove(session_key(id))
+        .map_err(VersionedError::Sled)?;
+    if let Some(user_id) = old.and_then(|old| parse_session(&old).ok()?.user_id) {
+        root.tree()
+            .remove(user_index_key(user_id, id))
+            .map_err(VersionedError::Sled)?;
+    }
+    Ok(())
+}
+
+/// State of live session, expired one is removed. Each use prolongs the session by `ttl`.
+pub fn load_session(
+    root: &Root,
+    key: &str,
+    ttl: Duration,
+) -> Result<Option<HashMap<String, String>>, VersionedError> {
+    let id = sha256_hex(key);
+    let db_key = session_key(&id);
+    let bytes = match root.tree().get(&db_key).map_err(VersionedError::Sled)? {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+    let mut record = parse_session(&bytes)?;
+    let now = unix_now();
+    if record.expires <= now {
+        remove_record(root, &id)?;
+        return Ok(None);
+    }
+    if now.saturating_sub(record.last_seen) >= TOUCH_INTERVAL {
+        record.last_seen = now;
+        record.expires = expires(record.user_id, now, ttl);
+        let new = serde_json::to_vec(&record).map_err(VersionedError::Json)?;
+        // lost race with update or revocation is fine, they are newer
+        let _ = root
+            .tree()
+            .compare_and_swap(&db_key, Some(bytes), Some(new))
+            .map_err(VersionedError::Sled)?;
+    }
+    Ok(Some(record.state))
+}
+
+/// Creates or replaces the session, `only_existing` prevents revived revoked sessions
+pub fn store_session(
+    root: &Root,
+    key: &str,
+    user_id: Option<u64>,
+    state: HashMap<String, String>,
+    ttl: Duration,
+    only_existing: bool,
+) -> Result<bool, VersionedError> {
+    let id = sha256_hex(key);
+    let db_key = session_key(&id);
+    let now = unix_now();
+    let mut record = SessionRecord {
+        user_id,
+        state,
+        created: now,
+        last_seen: now,
+        expires: expires(user_id, now, ttl),
+    };
+    let mut json_err = None;
+    let stored = root
+        .tree()
+        .fetch_and_update(&db_key, |old| {
+            if let Some(old) = old.and_then(|old| parse_session(old).ok()) {
+                record.created = old.created;
+            } else if only_existing {
+                return old.map(<[u8]>::to_vec);
+            }
+            match serde_json::to_vec(&record) {
+                Ok(bytes) => Some(bytes),
+                Err(err) => {
+                    json_err = Some(err);
+                    old.map(<[u8]>::to_vec)
+                }
+            }
+        })
+        .map_err(VersionedError::Sled)?;
+    if let Some(err) = json_err {
+        return Err(VersionedError::Json(err));
+    }
+    let stored = stored.is_some() || !only_existing;
+    if let (Some(user_id), true) = (user_id, stored) {
+        root.tree()
+            .insert(user_index_key(user_id, &id), &b""[..])
+            .map_err(VersionedError::Sled)?;
+    }
+    Ok(stored)
+}
+
+pub fn remove_session(root: &Root, key: &str) -> Result<(), VersionedError> {
+    remove_record(root, &sha256_hex(key))
+}
+
+/// Live sessions of the user, recently used first
+pub fn user_sessions(root: &Root, user_id: u64) -> Result<Vec<SessionInfo>, VersionedError> {
+    let now = unix_now();
+    let prefix = user_index_prefix(user_id);
+    let mut sessions = vec![];
+    for pair in root.tree().scan_prefix(&prefix) {
+        let (index_key, _) = pair.map_err(VersionedError::Sled)?;
+        let id = String::from_utf8_lossy(&index_key[prefix.len()..]).into_owned();
+        let record = match root
+            .tree()
+            .get(session_key(&id))
+            .map_err(VersionedError::Sled)?
+        {
+            Some(bytes) => parse_session(&bytes)?,
+            None => {
+                root.tree()
+                    .remove(&index_key)
+                    .map_err(VersionedError::Sled)?;
+                continue;
+            }
+        };
+        // session was reused by another user or logged out
+        if record.user_id != Some(user_id) {
+            root.tree()
+                .remove(&index_key)
+                .map_err(VersionedError::Sled)?;
+            continue;
+        }
+        if record.expires <= now {
+            continue;
+        }
+        sessions.push(SessionInfo {
+            id,
+            created: record.created,
+            last_seen: record.last_seen,
+            expires: record.expires,
+        });
+    }
+    sessions.sort_by_key(|info| std::cmp::Reverse(info.last_seen));
+    Ok(sessions)
+}
+
+/// Removes session of the user by its id
+pub fn revoke_session(root: &Root, user_id: u64, id: &str) -> Result<(), VersionedError> {
+    let key = session_key(id);
+    let bytes = root
+        .tree()
+        .get(&key)
+        .map_err(VersionedError::Sled)?
+        .ok_or(VersionedError::NotFound)?;
+    if parse_session(&bytes)?.user_id != Some(user_id) {
+        return Err(VersionedError::AccessDenied);
+    }
+    remove_record(root, id)
+}
+
+/// Logs the user out everywhere, returns number of removed sessions
+pub fn revoke_user_sessions(root: &Root, user_id: u64) -> Result<usize, VersionedError> {
+    let sessions = user_sessions(root, user_id)?;
+    for session in &sessions {
+        remove_record(root, &session.id)?;
+    }
+    Ok(sessions.len())
+}
+
+/// Removes expired sessions, anonymous ones expire after a day without use.
+/// Returns number of removed sessions.
+pub fn sweep_sessions(root: &Root) -> Result<usize, VersionedError> {
+    let now = unix_now();
+    let mut expired = vec![];
+    for pair in root.tree().scan_prefix(SESSION_PREFIX) {
+        let (key, value) = pair.map_err(VersionedError::Sled)?;
+        let id = String::from_utf8_lossy(&key[SESSION_PREFIX.len()..]).into_owned();
+        match parse_session(&value) {
+            Ok(record) if record.expires > now => {}
+            Ok(_) => expired.push(id),
+            Err(err) => {
+                eprintln!("Removing broken session {}: {:?}", id, err);
+                expired.push(id);
+            }
+        }
+    }
+    for id in &expired {
+        remove_record(root, id)?;
+    }
+    Ok(expired.len())
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    tools::{new_secret, sha256_hex, unix_now},
+    Root, VersionedError,
+};
+
+pub(super) const TOKEN_PREFIX: &str = "token/";
+/// Marks secrets issued by us, so they are easy to find in leaked scripts and logs
+const SECRET_PREFIX: &str = "fo4rp_";
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Personal API token, only SHA-256 of the secret is stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub user_id: u64,
+    pub name: String,
+    /// Permissions the token is limited to
+    pub scopes: Vec<String>,
+    pub created: u64,
+    pub last_used: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenInfo {
+    /// Hex of the secret's hash
+    pub id: String,
+    #[serde(flatten)]
+    pub token: ApiToken,
+}
+
+fn token_key(id: &str) -> String {
+    format!("{}{}", TOKEN_PREFIX, id)
+}
+
+fn parse_token(bytes: &[u8]) -> Result<ApiToken, VersionedError> {
+    serde_json::from_slice(bytes).map_err(VersionedError::Json)
+}
+
+/// Returns secret that should be shown to the user once and id of the new token
+pub fn create_token(
+    root: &Root,
+    user_id: u64,
+    name: String,
+    scopes: Vec<String>,
+) -> Result<(String, String), VersionedError> {
+    let secret = new_secret(SECRET_PREFIX);
+    let id = sha256_hex(&secret);
+    let token = ApiToken {
+        user_id,
+        name,
+        scopes,
+        created: unix_now(),
+        last_used: None,
+    };
+    let bytes = serde_json::to_vec(&token).map_err(VersionedError::Json)?;
+    root.tree()
+        .compare_and_swap(token_key(&id), None as Option<&[u8]>, Some(bytes))
+        .map_err(VersionedError::Sled)?
+        .map_err(|_| VersionedError::UnexpectedOldValue)?;
+    Ok((secret, id))
+}
+
+/// Looks up token by its secret and records its use
+pub fn authenticate(root: &Root, secret: &str) -> Result<Option<ApiToken>, VersionedError> {
+    if !secret.starts_with(SECRET_PREFIX) {
+        return Ok(None);
+    }
+    let now = unix_now();
+    let updated = root
+        .tree()
+        .update_and_fetch(token_key(&sha256_hex(secret)), |old| {
+            let old = old?;
+            match parse_token(old) {
+                Ok(mut token) => {
+                    token.last_used = Some(now);
+                    serde_json::to_vec(&token)
+                        .ok()
+                        .or_else(|| Some(old.to_vec()))
+                }
+                // keep broken record for inspection, it won't authenticate anyway
+                Err(_) => Some(old.to_vec()),
+            }
+        })
+        .map_err(VersionedError::Sled)?;
+    updated.map(|bytes| parse_token(&bytes)).transpose()
+}
+
+/// Tokens of the user, newest first
+pub fn user_tokens(root: &Root, user_id: u64) -> Result<Vec<TokenInfo>, VersionedError> {
+    let mut tokens = vec![];
+    for pair in root.tree().scan_prefix(TOKEN_PREFIX) {
+        let (key, value) = pair.map_err(VersionedError::Sled)?;
+        // owner of a broken record is unknown, the rest of the tokens should stay manageable
+        let token = match parse_token(&value) {
+            Ok(token) => token,
+            Err(err) => {
+                eprintln!(
+                    "Broken token record {}: {:?}",
+                    String::from_utf8_lossy(&key),
+                    err
+                );
+                continue;
+            }
+        };
+        if token.user_id != user_id {
+            continue;
+        }
+        let id = String::from_utf8_lossy(&key[TOKEN_PREFIX.len()..]).into_owned();
+        tokens.push(TokenInfo { id, token });
+    }
+    tokens.sort_by_key(|info| std::cmp::Reverse(info.token.created));
+    Ok(tokens)
+}
+
+/// Removes token of the user, tokens of others are left untouched
+pub fn revoke_token(root: &Root, user_id: u64, id: &str) -> Result<ApiToken, VersionedError> {
+    let key = token_key(id);
+    let old = root
+        .tree()
+        .get(&key)
+        .map_err(VersionedError::Sled)?
+        .ok_or(VersionedError::NotFound)?;
+    let token = parse_token(&old)?;
+    if token.user_id != user_id {
+        return Err(VersionedError::AccessDenied);
+    }
+    root.tree().remove(&key).map_err(VersionedError::Sled)?;
+    Ok(token)
+}
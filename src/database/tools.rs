@@ -1,4 +1,10 @@
-use std::convert::TryInto;
+use std::{
+    convert::TryInto,
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
 
 pub fn ivec_to_u32(ivec: sled::IVec) -> Result<u32, sled::IVec> {
     slice_to_u32(ivec.as_ref()).ok_or(ivec)
@@ -28,3 +34,35 @@ pub fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
     };
     Some(number.to_be_bytes().to_vec())
 }
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_secs())
+        .unwrap_or(0)
+}
+
+/// Hex of SHA-256, secrets are stored only as hashes
+pub fn sha256_hex(secret: &str) -> String {
+    let hash = Sha256::digest(secret.as_bytes());
+    let mut hex = String::with_capacity(hash.len() * 2);
+    for byte in hash {
+        write!(hex, "{:02x}", byte).expect("Write to String");
+    }
+    hex
+}
+
+/// Random URL-safe secret with a recognizable prefix
+pub fn new_secret(prefix: &str) -> String {
+    let random: [u8; 32] = rand::random();
+    format!(
+        "{}{}",
+        prefix,
+        base64::encode_config(random, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Compares secrets in time that doesn't depend on where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -1,8 +1,14 @@
-use std::{fmt::Write, ops::Bound};
+use std::{
+    collections::BTreeMap,
+    fmt::{UpperHex, Write},
+    ops::Bound,
+};
+
+use serde::Serialize;
 
 use super::{
-    tools::ivec_to_u32,
-    versioned::{get_value, new_leaf, VersionedError},
+    tools::{ivec_to_u32, slice_to_u64, unix_now},
+    versioned::{get_value, list_values, new_leaf, set_value, update_branch, VersionedError},
     ArcSlice,
 };
 
@@ -19,9 +25,9 @@ impl Root {
         &self.tree
     }
 
-    pub fn trunk<B: Bark>(&self, id: u32, max_ver: Option<u32>, bark: B) -> Trunk<B> {
+    pub fn trunk<B: Bark>(&self, id: B::Id, max_ver: Option<u32>, bark: B) -> Trunk<B> {
         Trunk {
-            id,
+            key_id: bark.format_id(id),
             versions: versions(max_ver),
             bark,
             root: self,
@@ -29,19 +35,40 @@ impl Root {
     }
 }
 
+fn new_secret() -> u32 {
+    let mut secret = 0u32;
+    while secret == 0 {
+        secret = rand::random();
+    }
+    secret
+}
+
 fn versions(ver: Option<u32>) -> (Bound<u32>, Bound<u32>) {
     ver.map(|v| (Bound::Unbounded, Bound::Included(v)))
         .unwrap_or((Bound::Unbounded, Bound::Unbounded))
 }
 
 pub trait Bark {
+    type Id: Copy + UpperHex;
+
     fn secret(&self) -> &str;
     fn counter(&self) -> &str;
+    fn timestamp(&self) -> &str;
     fn trunk(&self) -> &str;
+
+    /// Id part of the keys, padded to the width of `Id` so keys of the trunk sort and scan by id
+    fn format_id(&self, id: Self::Id) -> String {
+        format!(
+            "{:0width$X}",
+            id,
+            width = std::mem::size_of::<Self::Id>() * 2
+        )
+    }
 }
 
 pub struct Trunk<'a, T: Bark> {
-    id: u32,
+    /// Id formatted by `Bark::format_id`
+    key_id: String,
     versions: (Bound<u32>, Bound<u32>),
     bark: T,
     root: &'a Root,
@@ -54,7 +81,7 @@ impl<'a, T: Bark> Trunk<'a, T> {
 
     fn branch_key(&self, branch: &str) -> Result<String, VersionedError> {
         let mut key = String::with_capacity(32);
-        write!(key, "{}/{:08X}/{}", self.bark.trunk(), self.id, branch)
+        write!(key, "{}/{}/{}", self.bark.trunk(), self.key_id, branch)
             .map_err(VersionedError::WriteFmt)?;
         Ok(key)
     }
@@ -63,9 +90,9 @@ impl<'a, T: Bark> Trunk<'a, T> {
         let mut key = String::with_capacity(32);
         write!(
             key,
-            "{}/{:08X}/{}/{:08X}",
+            "{}/{}/{}/{:08X}",
             self.bark.trunk(),
-            self.id,
+            self.key_id,
             branch,
             leaf
         )
@@ -77,7 +104,7 @@ impl<'a, T: Bark> Trunk<'a, T> {
         let ver_secret = get_value(
             self.root,
             self.bark.trunk(),
-            self.id,
+            &self.key_id,
             self.bark.secret(),
             self.versions,
             ivec_to_u32,
@@ -101,7 +128,7 @@ impl<'a, T: Bark> Trunk<'a, T> {
         let (ver, data) = get_value(
             self.root,
             self.bark.trunk(),
-            self.id,
+            &self.key_id,
             branch,
             self.versions,
             Ok,
@@ -115,22 +142,49 @@ impl<'a, T: Bark> Trunk<'a, T> {
     }
 
     pub fn set_versioned(&self, branch: &str, data: Vec<u8>) -> Result<Leaf<()>, VersionedError> {
-        let mut secret = 0u32;
-        while secret == 0 {
-            secret = rand::random();
-        }
-        let secret_data = secret.to_be_bytes().to_vec();
+        let secret = new_secret();
+        let ver = new_leaf(
+            self.root,
+            self.bark.trunk(),
+            &self.key_id,
+            self.bark.counter(),
+            [
+                (branch, data),
+                (self.bark.secret(), secret.to_be_bytes().to_vec()),
+                (self.bark.timestamp(), unix_now().to_be_bytes().to_vec()),
+            ],
+        )?;
+        self.new_versioned_leaf(ver, secret)
+    }
 
+    /// Same as `set_versioned`, but `extra` value is written under the same version
+    /// before the data, so readers never see the data without it.
+    pub fn set_versioned_with(
+        &self,
+        branch: &str,
+        data: Vec<u8>,
+        extra: (&str, Vec<u8>),
+    ) -> Result<Leaf<()>, VersionedError> {
+        let secret = new_secret();
         let ver = new_leaf(
             self.root,
             self.bark.trunk(),
-            self.id,
+            &self.key_id,
             self.bark.counter(),
-            [(branch, data), (self.bark.secret(), secret_data)],
+            [
+                extra,
+                (branch, data),
+                (self.bark.secret(), secret.to_be_bytes().to_vec()),
+                (self.bark.timestamp(), unix_now().to_be_bytes().to_vec()),
+            ],
         )?;
+        self.new_versioned_leaf(ver, secret)
+    }
+
+    fn new_versioned_leaf(&self, ver: u32, secret: u32) -> Result<Leaf<()>, VersionedError> {
         println!(
             "new image, id: {}, ver: {}, secret: {}",
-            self.id, ver, secret
+            self.key_id, ver, secret
         );
         Ok(Leaf {
             data: (),
@@ -139,20 +193,134 @@ impl<'a, T: Bark> Trunk<'a, T> {
         })
     }
 
-    // TODO: Use or remove
-    /*pub fn update_branch<V, F>(&self, tree: &TreeRoot, branch: &str, f: F) -> Result<Option<sled::IVec>, VersionedError>
+    pub fn update_bare_branch<V, F>(
+        &self,
+        branch: &str,
+        func: F,
+    ) -> Result<Option<sled::IVec>, VersionedError>
     where
-        F: Fn(Option<&[u8]>) -> Option<V>>,
+        F: Fn(Option<&[u8]>) -> Option<V>,
         sled::IVec: From<V>,
     {
-        update_branch(
-            tree,
-            self.trunk,
-            self.id,
+        update_branch(self.root, self.bark.trunk(), &self.key_id, branch, func)
+    }
+
+    /// Lists versions of the branch, oldest first.
+    /// Write timestamps are missing for versions written before they were recorded.
+    pub fn history(&self, branch: &str) -> Result<Vec<LeafInfo>, VersionedError> {
+        let trunk = self.bark.trunk();
+        let sizes = list_values(
+            self.root,
+            trunk,
+            &self.key_id,
             branch,
-            f,
+            self.versions,
+            |value| Ok(value.len()),
+        )?;
+        let secrets: BTreeMap<u32, u32> = list_values(
+            self.root,
+            trunk,
+            &self.key_id,
+            self.bark.secret(),
+            self.versions,
+            ivec_to_u32,
+        )?
+        .into_iter()
+        .collect();
+        let timestamps: BTreeMap<u32, u64> = list_values(
+            self.root,
+            trunk,
+            &self.key_id,
+            self.bark.timestamp(),
+            self.versions,
+            |value| slice_to_u64(&value).ok_or(value),
+        )?
+        .into_iter()
+        .collect();
+
+        Ok(sizes
+            .into_iter()
+            .map(|(ver, size)| LeafInfo {
+                ver,
+                size,
+                written: timestamps.get(&ver).copied(),
+                secret: secrets.get(&ver).copied(),
+            })
+            .collect())
+    }
+
+    pub fn get_version(&self, branch: &str, ver: u32) -> Result<Option<ArcSlice>, VersionedError> {
+        Ok(get_value(
+            self.root,
+            self.bark.trunk(),
+            &self.key_id,
+            branch,
+            ver..=ver,
+            Ok,
+        )?
+        .map(|(_ver, data)| data))
+    }
+
+    pub fn set_version(&self, branch: &str, ver: u32, data: Vec<u8>) -> Result<(), VersionedError> {
+        set_value(
+            self.root,
+            self.bark.trunk(),
+            &self.key_id,
+            branch,
+            ver,
+            data,
+        )?;
+        Ok(())
+    }
+
+    /// Lists versions of the branch with their values, oldest first
+    pub fn list(&self, branch: &str) -> Result<Vec<(u32, ArcSlice)>, VersionedError> {
+        list_values(
+            self.root,
+            self.bark.trunk(),
+            &self.key_id,
+            branch,
+            self.versions,
+            Ok,
         )
-    }*/
+    }
+
+    pub fn list_secrets(&self) -> Result<Vec<(u32, u32)>, VersionedError> {
+        list_values(
+            self.root,
+            self.bark.trunk(),
+            &self.key_id,
+            self.bark.secret(),
+            self.versions,
+            ivec_to_u32,
+        )
+    }
+
+    pub fn get_latest(&self, branch: &str) -> Result<Option<(u32, ArcSlice)>, VersionedError> {
+        get_value(
+            self.root,
+            self.bark.trunk(),
+            &self.key_id,
+            branch,
+            self.versions,
+            Ok,
+        )
+    }
+
+    pub fn append(
+        &self,
+        counter: &str,
+        branch: &str,
+        data: Vec<u8>,
+    ) -> Result<u32, VersionedError> {
+        new_leaf(
+            self.root,
+            self.bark.trunk(),
+            &self.key_id,
+            counter,
+            [(branch, data)],
+        )
+    }
 
     pub fn get_bare_branch(&self, branch: &str) -> Result<sled::IVec, VersionedError> {
         let key = self.branch_key(branch)?;
@@ -206,6 +374,23 @@ impl<'a, T: Bark> Trunk<'a, T> {
         }
         Err(VersionedError::ConcurrentWrites)
     }
+
+    pub fn set_bare_branch(
+        &self,
+        branch: &str,
+        value: &[u8],
+    ) -> Result<Option<sled::IVec>, VersionedError> {
+        let key = self.branch_key(branch)?;
+        self.root
+            .tree()
+            .insert(&key, value)
+            .map_err(VersionedError::Sled)
+    }
+
+    pub fn remove_bare_branch(&self, branch: &str) -> Result<Option<sled::IVec>, VersionedError> {
+        let key = self.branch_key(branch)?;
+        self.root.tree().remove(&key).map_err(VersionedError::Sled)
+    }
 }
 
 // Set image
@@ -214,6 +399,15 @@ impl<'a, T: Bark> Trunk<'a, T> {
     unimplemented!()
 }*/
 
+#[derive(Debug, Clone, Serialize)]
+pub struct LeafInfo {
+    pub ver: u32,
+    pub size: usize,
+    /// Unix timestamp in seconds
+    pub written: Option<u64>,
+    pub secret: Option<u32>,
+}
+
 pub struct Leaf<T> {
     pub data: T,
     pub ver: u32,
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    tools::slice_to_u32,
+    tree::{Bark, Trunk},
+    versioned::VersionedError,
+};
+
+const SETTINGS_BRANCH: &str = "settings";
+const ROLES_BRANCH: &str = "discord_roles";
+/// Index of owned characters, u32 ids one after another
+const CHARACTERS_BRANCH: &str = "characters";
+
+/// Per-account data of Discord user, keyed by user id
+#[derive(Default)]
+pub struct UserTrunk;
+
+impl Bark for UserTrunk {
+    type Id = u64;
+
+    fn secret(&self) -> &str {
+        "secret"
+    }
+
+    fn counter(&self) -> &str {
+        "ver"
+    }
+
+    fn timestamp(&self) -> &str {
+        "time"
+    }
+
+    fn trunk(&self) -> &str {
+        "user"
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserSettings {
+    /// Preferred language code, e.g. "ru" or "en"
+    pub language: Option<String>,
+    /// Receive notifications about own characters from the server
+    pub notifications: bool,
+    /// How many characters user can link, set by admins
+    pub max_characters: Option<u32>,
+}
+
+/// Discord roles of the user saved on the last successful lookup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedRoles {
+    pub name: String,
+    pub nick: Option<String>,
+    /// Ids and names of roles in the main guild
+    pub roles: Vec<(u64, String)>,
+    /// Unix time of the lookup
+    pub fetched: u64,
+}
+
+impl<'a> Trunk<'a, UserTrunk> {
+    pub fn get_settings(&self) -> Result<UserSettings, VersionedError> {
+        match self.get_bare_branch(SETTINGS_BRANCH) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(VersionedError::Json),
+            Err(VersionedError::NotFound) => Ok(UserSettings::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn set_settings(&self, settings: &UserSettings) -> Result<(), VersionedError> {
+        let bytes = serde_json::to_vec(settings).map_err(VersionedError::Json)?;
+        self.set_bare_branch(SETTINGS_BRANCH, &bytes)?;
+        Ok(())
+    }
+
+    pub fn get_cached_roles(&self) -> Result<Option<CachedRoles>, VersionedError> {
+        match self.get_bare_branch(ROLES_BRANCH) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(VersionedError::Json),
+            Err(VersionedError::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn set_cached_roles(&self, roles: &CachedRoles) -> Result<(), VersionedError> {
+        let bytes = serde_json::to_vec(roles).map_err(VersionedError::Json)?;
+        self.set_bare_branch(ROLES_BRANCH, &bytes)?;
+        Ok(())
+    }
+
+    /// Ids of owned characters, `None` if the index wasn't built yet
+    pub fn get_characters(&self) -> Result<Option<Vec<u32>>, VersionedError> {
+        match self.get_bare_branch(CHARACTERS_BRANCH) {
+            Ok(bytes) => Ok(Some(parse_characters(&bytes))),
+            Err(VersionedError::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn set_characters(&self, chars: &[u32]) -> Result<(), VersionedError> {
+        self.set_bare_branch(CHARACTERS_BRANCH, &characters_bytes(chars))?;
+        Ok(())
+    }
+
+    /// Adds the character to the index or removes it
+    pub fn update_characters(&self, char_id: u32, owned: bool) -> Result<(), VersionedError> {
+        self.update_bare_branch(CHARACTERS_BRANCH, |old| {
+            let mut chars = old.map(parse_characters).unwrap_or_default();
+            chars.retain(|id| *id != char_id);
+            if owned {
+                chars.push(char_id);
+                chars.sort_unstable();
+            }
+            Some(characters_bytes(&chars))
+        })?;
+        Ok(())
+    }
+}
+
+fn parse_characters(bytes: &[u8]) -> Vec<u32> {
+    bytes.chunks_exact(4).filter_map(slice_to_u32).collect()
+}
+
+fn characters_bytes(chars: &[u32]) -> Vec<u8> {
+    chars.iter().flat_map(|id| id.to_be_bytes()).collect()
+}
@@ -20,7 +20,10 @@ pub enum VersionedError {
     VersionUtf(std::str::Utf8Error),
     VersionParse(std::num::ParseIntError),
     ValueParse(ArcSlice),
+    Json(serde_json::Error),
     AccessDenied,
+    TooManyAttempts,
+    LimitReached,
     CounterInvalid,
     UnexpectedOldValue,
     NotFound,
@@ -39,17 +42,18 @@ impl From<BlockingError> for VersionedError {
 const MIN_U32: &str = "00000000";
 const MAX_U32: &str = "FFFFFFFF";
 
-pub fn get_value<T, R: RangeBounds<u32>, F: Fn(IVec) -> Result<T, IVec>>(
-    root: &Root,
+type KeyRange = (Bound<String>, Bound<String>);
+
+// Builds range of keys for versions of the branch, also returns length of the common prefix
+fn version_range<R: RangeBounds<u32>>(
     trunk: &str,
-    id: u32,
+    id: &str,
     branch: &str,
-    ver: R,
-    parse: F,
-) -> Result<Option<(u32, T)>, VersionedError> {
+    ver: &R,
+) -> Result<(usize, KeyRange), VersionedError> {
     let mut from = String::with_capacity(32);
 
-    write!(from, "{}/{:08X}/{}/", trunk, id, branch).map_err(VersionedError::WriteFmt)?;
+    write!(from, "{}/{}/{}/", trunk, id, branch).map_err(VersionedError::WriteFmt)?;
     let base_len = from.len();
 
     let mut to = from.clone();
@@ -84,16 +88,33 @@ pub fn get_value<T, R: RangeBounds<u32>, F: Fn(IVec) -> Result<T, IVec>>(
         }
     };
 
+    Ok((base_len, (lo, hi)))
+}
+
+fn parse_version(full_key: &[u8], base_len: usize) -> Result<u32, VersionedError> {
+    let key = full_key
+        .get(base_len..)
+        .ok_or(VersionedError::VersionEmpty)?;
+    let key = std::str::from_utf8(key).map_err(VersionedError::VersionUtf)?;
+    u32::from_str_radix(key, 16).map_err(VersionedError::VersionParse)
+}
+
+pub fn get_value<T, R: RangeBounds<u32>, F: Fn(IVec) -> Result<T, IVec>>(
+    root: &Root,
+    trunk: &str,
+    id: &str,
+    branch: &str,
+    ver: R,
+    parse: F,
+) -> Result<Option<(u32, T)>, VersionedError> {
+    let (base_len, (lo, hi)) = version_range(trunk, id, branch, &ver)?;
+
     println!("from: {:?}, to: {:?}", &hi, &lo);
 
     for pair in root.tree().range((lo, hi)).rev() {
         let (full_key, value) = pair.map_err(VersionedError::Sled)?;
         println!("full_key: {:?}", std::str::from_utf8(&full_key));
-        let key = full_key
-            .get(base_len..)
-            .ok_or(VersionedError::VersionEmpty)?;
-        let key = std::str::from_utf8(key).map_err(VersionedError::VersionUtf)?;
-        let key = u32::from_str_radix(key, 16).map_err(VersionedError::VersionParse)?;
+        let key = parse_version(&full_key, base_len)?;
         if !ver.contains(&key) {
             eprintln!("Strange version: {:?}", key);
             continue;
@@ -104,10 +125,35 @@ pub fn get_value<T, R: RangeBounds<u32>, F: Fn(IVec) -> Result<T, IVec>>(
     Ok(None)
 }
 
+/// Lists all versions of the branch in the range, oldest first
+pub fn list_values<T, R: RangeBounds<u32>, F: Fn(IVec) -> Result<T, IVec>>(
+    root: &Root,
+    trunk: &str,
+    id: &str,
+    branch: &str,
+    ver: R,
+    parse: F,
+) -> Result<Vec<(u32, T)>, VersionedError> {
+    let (base_len, range) = version_range(trunk, id, branch, &ver)?;
+
+    let mut values = vec![];
+    for pair in root.tree().range(range) {
+        let (full_key, value) = pair.map_err(VersionedError::Sled)?;
+        let key = parse_version(&full_key, base_len)?;
+        if !ver.contains(&key) {
+            eprintln!("Strange version: {:?}", key);
+            continue;
+        }
+        let value = parse(value).map_err(VersionedError::ValueParse)?;
+        values.push((key, value));
+    }
+    Ok(values)
+}
+
 pub fn update_branch<V, F>(
     root: &Root,
     trunk: &str,
-    id: u32,
+    id: &str,
     branch: &str,
     func: F,
 ) -> Result<Option<IVec>, VersionedError>
@@ -116,13 +162,18 @@ where
     IVec: From<V>,
 {
     let mut key = String::with_capacity(32);
-    write!(key, "{}/{:08X}/{}", trunk, id, branch).map_err(VersionedError::WriteFmt)?;
+    write!(key, "{}/{}/{}", trunk, id, branch).map_err(VersionedError::WriteFmt)?;
     root.tree()
         .update_and_fetch(key, func)
         .map_err(VersionedError::Sled)
 }
 
-pub fn inc_counter(root: &Root, trunk: &str, id: u32, branch: &str) -> Result<u32, VersionedError> {
+pub fn inc_counter(
+    root: &Root,
+    trunk: &str,
+    id: &str,
+    branch: &str,
+) -> Result<u32, VersionedError> {
     update_branch(root, trunk, id, branch, increment).and_then(|opt| {
         opt.and_then(|ivec| slice_to_u32(ivec.as_ref()))
             .ok_or(VersionedError::CounterInvalid)
@@ -132,7 +183,7 @@ pub fn inc_counter(root: &Root, trunk: &str, id: u32, branch: &str) -> Result<u3
 pub fn set_value<V>(
     root: &Root,
     trunk: &str,
-    id: u32,
+    id: &str,
     branch: &str,
     ver: u32,
     value: V,
@@ -141,7 +192,7 @@ where
     IVec: From<V>,
 {
     let mut key = String::with_capacity(32);
-    write!(key, "{}/{:08X}/{}/{:08X}", trunk, id, branch, ver).map_err(VersionedError::WriteFmt)?;
+    write!(key, "{}/{}/{}/{:08X}", trunk, id, branch, ver).map_err(VersionedError::WriteFmt)?;
 
     root.tree().insert(key, value).map_err(VersionedError::Sled)
 }
@@ -149,7 +200,7 @@ where
 pub fn new_leaf<V, const SIZE: usize>(
     root: &Root,
     trunk: &str,
-    id: u32,
+    id: &str,
     counter: &str,
     branch_values: [(&str, V); SIZE],
 ) -> Result<u32, VersionedError>
@@ -161,7 +212,10 @@ where
     for (branch, value) in branch_values {
         let old_value = set_value(root, trunk, id, branch, ver, value)?;
         if old_value.is_some() {
-            eprintln!("Unexpected old value: {}/{}/{}/{}", trunk, id, branch, ver);
+            eprintln!(
+                "Unexpected old value: {}/{}/{}/{:08X}",
+                trunk, id, branch, ver
+            );
             //return Err(VersionedError::UnexpectedOldValue)
         }
     }
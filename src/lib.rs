@@ -2,6 +2,8 @@ pub mod bridge;
 pub mod config;
 pub mod critters_db;
 pub mod database;
+pub mod palette;
+pub mod rate_limit;
 mod templates;
 pub mod utils;
 pub mod web;
@@ -0,0 +1,234 @@
+use std::{io, path::Path};
+
+use image::{Rgb, RgbImage};
+
+use crate::config::Dither;
+
+const COLORS: usize = 256;
+/// Index 0 is transparent, indices 229..=254 are animated by the game (fire, monitors, etc.)
+const USABLE: std::ops::RangeInclusive<u8> = 1..=228;
+
+/// Classic game palette, `COLOR.PAL` stores 256 RGB triples with 6-bit components
+pub struct Palette {
+    colors: [[u8; 3]; COLORS],
+}
+
+impl Palette {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        Self::parse(&bytes)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Palette is too short"))
+    }
+
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..COLORS * 3)?;
+        let mut colors = [[0u8; 3]; COLORS];
+        for (color, rgb) in colors.iter_mut().zip(bytes.chunks_exact(3)) {
+            for (channel, &value) in color.iter_mut().zip(rgb) {
+                // values above 63 aren't colors, game treats them as black
+                *channel = if value < 64 { value * 4 } else { 0 };
+            }
+        }
+        Some(Palette { colors })
+    }
+
+    pub fn color(&self, index: u8) -> [u8; 3] {
+        self.colors[index as usize]
+    }
+
+    fn nearest(&self, rgb: [i32; 3]) -> u8 {
+        USABLE
+            .min_by_key(|&index| {
+                let color = self.colors[index as usize];
+                (0..3)
+                    .map(|channel| {
+                        let diff = rgb[channel] - color[channel] as i32;
+                        diff * diff
+                    })
+                    .sum::<i32>()
+            })
+            .expect("Non-empty range")
+    }
+
+    /// Maps every pixel to the nearest palette color, returns palette indices row by row
+    pub fn quantize(&self, image: &RgbImage, dither: Dither) -> Vec<u8> {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let mut indices = Vec::with_capacity(width * height);
+        // accumulated error for the current and the next row
+        let mut errors = vec![[0i32; 3]; width * 2];
+
+        for y in 0..height {
+            let (current, next) = errors.split_at_mut(width);
+            for x in 0..width {
+                let pixel = image.get_pixel(x as u32, y as u32).0;
+                let mut rgb = [0i32; 3];
+                for ((value, &channel), error) in rgb.iter_mut().zip(&pixel).zip(&current[x]) {
+                    *value = (channel as i32 + error / 16).clamp(0, 255);
+                }
+                let index = self.nearest(rgb);
+                indices.push(index);
+
+                if dither == Dither::FloydSteinberg {
+                    let mut error = [0i32; 3];
+                    for ((error, value), &color) in
+                        error.iter_mut().zip(&rgb).zip(&self.colors[index as usize])
+                    {
+                        *error = value - color as i32;
+                    }
+                    if x + 1 < width {
+                        spread(&mut current[x + 1], &error, 7);
+                        spread(&mut next[x + 1], &error, 1);
+                    }
+                    if x > 0 {
+                        spread(&mut next[x - 1], &error, 3);
+                    }
+                    spread(&mut next[x], &error, 5);
+                }
+            }
+            errors.copy_within(width.., 0);
+            errors[width..].fill([0; 3]);
+        }
+        indices
+    }
+
+    /// Renders indices back to RGB, the way the game shows them
+    pub fn to_image(&self, width: u32, height: u32, indices: &[u8]) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            Rgb(self.color(indices[(y * width + x) as usize]))
+        })
+    }
+}
+
+// Floyd-Steinberg weights are in sixteenths
+fn spread(cell: &mut [i32; 3], error: &[i32; 3], weight: i32) {
+    for (cell, error) in cell.iter_mut().zip(error) {
+        *cell += error * weight;
+    }
+}
+
+/// Writes single frame, single direction FRM image, all numbers are big-endian
+pub fn write_frm(width: u16, height: u16, indices: &[u8]) -> Vec<u8> {
+    const VERSION: u32 = 4;
+    const DIRECTIONS: usize = 6;
+
+    let pixels = width as usize * height as usize;
+    assert_eq!(indices.len(), pixels);
+    let frame_size = 12 + pixels;
+
+    let mut frm = Vec::with_capacity(62 + frame_size);
+    frm.extend_from_slice(&VERSION.to_be_bytes());
+    frm.extend_from_slice(&0u16.to_be_bytes()); // fps
+    frm.extend_from_slice(&0u16.to_be_bytes()); // action frame
+    frm.extend_from_slice(&1u16.to_be_bytes()); // frames per direction
+    for _ in 0..DIRECTIONS * 2 {
+        frm.extend_from_slice(&0i16.to_be_bytes()); // shifts by x and y
+    }
+    for _ in 0..DIRECTIONS {
+        frm.extend_from_slice(&0u32.to_be_bytes()); // frame offsets, all directions share one
+    }
+    frm.extend_from_slice(&(frame_size as u32).to_be_bytes());
+
+    frm.extend_from_slice(&width.to_be_bytes());
+    frm.extend_from_slice(&height.to_be_bytes());
+    frm.extend_from_slice(&(pixels as u32).to_be_bytes());
+    frm.extend_from_slice(&0i16.to_be_bytes()); // offset x
+    frm.extend_from_slice(&0i16.to_be_bytes()); // offset y
+    frm.extend_from_slice(indices);
+    frm
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WHITE: u8 = 10;
+    const RED: u8 = 20;
+    const ANIMATED_GREEN: u8 = 240;
+
+    /// Black everywhere except a few colors
+    fn test_palette() -> Palette {
+        let mut bytes = vec![0u8; COLORS * 3];
+        bytes[WHITE as usize * 3..][..3].copy_from_slice(&[63, 63, 63]);
+        bytes[RED as usize * 3..][..3].copy_from_slice(&[63, 0, 0]);
+        bytes[ANIMATED_GREEN as usize * 3..][..3].copy_from_slice(&[0, 63, 0]);
+        Palette::parse(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(Palette::parse(&[0u8; COLORS * 3 - 1]).is_none());
+
+        let mut bytes = vec![0u8; COLORS * 3];
+        bytes[3..6].copy_from_slice(&[1, 63, 64]);
+        let palette = Palette::parse(&bytes).unwrap();
+        assert_eq!(palette.color(1), [4, 252, 0]);
+    }
+
+    #[test]
+    fn test_quantize_exact() {
+        let palette = test_palette();
+        let image = RgbImage::from_fn(3, 1, |x, _| match x {
+            0 => Rgb([252, 252, 252]),
+            1 => Rgb([250, 3, 0]),
+            _ => Rgb([0, 0, 0]),
+        });
+        assert_eq!(palette.quantize(&image, Dither::None), vec![WHITE, RED, 1]);
+    }
+
+    #[test]
+    fn test_quantize_skips_reserved() {
+        let palette = test_palette();
+        let image = RgbImage::from_pixel(2, 2, Rgb([0, 252, 0]));
+        for dither in [Dither::None, Dither::FloydSteinberg].iter().copied() {
+            for index in palette.quantize(&image, dither) {
+                assert!(USABLE.contains(&index), "{} isn't usable", index);
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantize_dither() {
+        let palette = test_palette();
+        let image = RgbImage::from_pixel(16, 16, Rgb([126, 126, 126]));
+
+        let flat = palette.quantize(&image, Dither::None);
+        assert!(flat.iter().all(|&index| index == flat[0]));
+
+        let dithered = palette.quantize(&image, Dither::FloydSteinberg);
+        let white = dithered.iter().filter(|&&index| index == WHITE).count();
+        // half of 256 pixels, give or take the edges
+        assert!((100..=156).contains(&white), "{} white pixels", white);
+        assert!(dithered.iter().all(|&index| index == WHITE || index == 1));
+    }
+
+    #[test]
+    fn test_to_image() {
+        let palette = test_palette();
+        let image = palette.to_image(2, 1, &[RED, WHITE]);
+        assert_eq!(image.get_pixel(0, 0), &Rgb([252, 0, 0]));
+        assert_eq!(image.get_pixel(1, 0), &Rgb([252, 252, 252]));
+    }
+
+    #[test]
+    fn test_write_frm() {
+        let indices = [1, 2, 3, 4, 5, 6];
+        let frm = write_frm(3, 2, &indices);
+        assert_eq!(frm.len(), 62 + 12 + indices.len());
+        assert_eq!(&frm[..4], &4u32.to_be_bytes());
+        // frames per direction
+        assert_eq!(&frm[8..10], &1u16.to_be_bytes());
+        // size of frame data
+        assert_eq!(&frm[58..62], &18u32.to_be_bytes());
+        // frame header: width, height, pixel count
+        assert_eq!(&frm[62..64], &3u16.to_be_bytes());
+        assert_eq!(&frm[64..66], &2u16.to_be_bytes());
+        assert_eq!(&frm[66..70], &6u32.to_be_bytes());
+        assert_eq!(&frm[74..], &indices);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_write_frm_wrong_size() {
+        write_frm(3, 3, &[0; 6]);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::config::Limit;
+
+/// Buckets are forgotten once they are full again, but not checked on every call
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Holds up to `burst` tokens, one is taken by every request and they are refilled
+/// at `per_minute` rate.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: &Limit) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second()).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// Takes a token or tells how long to wait for the next one
+    pub fn take(&mut self, limit: &Limit) -> Result<(), Duration> {
+        self.take_at(limit, Instant::now())
+    }
+
+    fn take_at(&mut self, limit: &Limit, now: Instant) -> Result<(), Duration> {
+        if !limit.enabled() {
+            return Ok(());
+        }
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.per_second(),
+            ))
+        }
+    }
+
+    fn is_full(&self, limit: &Limit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.per_second() >= limit.burst as f64
+    }
+}
+
+/// Separate token bucket for every key, like IP address or user id
+pub struct RateLimiter<K> {
+    limit: Limit,
+    buckets: Mutex<Buckets<K>>,
+}
+
+struct Buckets<K> {
+    map: HashMap<K, TokenBucket>,
+    swept: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(limit: Limit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> &Limit {
+        &self.limit
+    }
+
+    /// Takes a token from the bucket of `key`, `Err` holds time before retry
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        if !self.limit.enabled() {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock();
+        let now = Instant::now();
+        if now.saturating_duration_since(buckets.swept) >= SWEEP_INTERVAL {
+            let limit = &self.limit;
+            buckets
+                .map
+                .retain(|_key, bucket| !bucket.is_full(limit, now));
+            buckets.swept = now;
+        }
+        let limit = &self.limit;
+        buckets
+            .map
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(limit))
+            .take(limit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::LimitKey;
+
+    fn limit(burst: u32, per_minute: u32) -> Limit {
+        Limit {
+            burst,
+            per_minute,
+            key: LimitKey::Ip,
+        }
+    }
+
+    #[test]
+    fn test_burst() {
+        let limit = limit(3, 60);
+        let mut bucket = TokenBucket::new(&limit);
+        let now = bucket.updated;
+        for _ in 0..3 {
+            assert_eq!(bucket.take_at(&limit, now), Ok(()));
+        }
+        assert_eq!(bucket.take_at(&limit, now), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_refill() {
+        let limit = limit(2, 30);
+        let mut bucket = TokenBucket::new(&limit);
+        let start = bucket.updated;
+        assert!(bucket.take_at(&limit, start).is_ok());
+        assert!(bucket.take_at(&limit, start).is_ok());
+        assert!(bucket.take_at(&limit, start).is_err());
+
+        // half of a token after a second, a whole one after two
+        let retry = bucket.take_at(&limit, start + Duration::from_secs(1));
+        assert_eq!(retry, Err(Duration::from_secs(1)));
+        assert!(bucket
+            .take_at(&limit, start + Duration::from_secs(2))
+            .is_ok());
+        assert!(bucket
+            .take_at(&limit, start + Duration::from_secs(2))
+            .is_err());
+    }
+
+    #[test]
+    fn test_refill_up_to_burst() {
+        let limit = limit(2, 60);
+        let mut bucket = TokenBucket::new(&limit);
+        let start = bucket.updated;
+        assert!(bucket.take_at(&limit, start).is_ok());
+        assert!(!bucket.is_full(&limit, start));
+
+        let later = start + Duration::from_secs(3600);
+        assert!(bucket.is_full(&limit, later));
+        assert!(bucket.take_at(&limit, later).is_ok());
+        assert!(bucket.take_at(&limit, later).is_ok());
+        assert!(bucket.take_at(&limit, later).is_err());
+    }
+
+    #[test]
+    fn test_disabled() {
+        for limit in [limit(0, 60), limit(5, 0)].iter() {
+            let mut bucket = TokenBucket::new(limit);
+            for _ in 0..100 {
+                assert_eq!(bucket.take(limit), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_limiter_keys() {
+        let limiter = RateLimiter::new(limit(1, 1));
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_err());
+        assert!(limiter.check("b").is_ok());
+    }
+}
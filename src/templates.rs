@@ -3,7 +3,7 @@ use lazy_static::lazy_static;
 use serde::Serialize;
 use tera::Tera;
 
-use crate::config::Host;
+use crate::{config::Host, database::UserSettings};
 
 const TEMPLATES_PATH: &str = "templates/**/*";
 const CSS_PATH: &str = "static/charsheet.css";
@@ -67,6 +67,11 @@ lazy_static! {
 #[derive(Debug, Default)]
 pub struct RenderConfig<'a> {
     pub host: Option<&'a Host>,
+    pub settings: Option<&'a UserSettings>,
+    /// Who the admin is looking at the page as, shows the banner
+    pub impersonating: Option<&'a str>,
+    /// Hidden CSRF token field for POST forms
+    pub csrf_input: Option<&'a str>,
 }
 
 #[cfg(not(feature = "live_reload"))]
@@ -79,6 +84,14 @@ pub fn render<T: Serialize>(
     if let Some(host) = config.host {
         context.insert("files_url", &host.web_url(""));
     }
+    if let Some(settings) = config.settings {
+        context.insert("user_settings", settings);
+    }
+    if let Some(impersonating) = config.impersonating {
+        context.insert("impersonating", impersonating);
+    }
+    // empty for anonymous visitors, so forms don't need to check it
+    context.insert("csrf_input", config.csrf_input.unwrap_or(""));
     Ok(TEMPLATES.tera.render(template, &context)?)
 }
 #[cfg(feature = "live_reload")]
@@ -92,6 +105,14 @@ pub fn render<T: Serialize>(
     if let Some(host) = config.host {
         context.insert("files_url", &host.web_url(""));
     }
+    if let Some(settings) = config.settings {
+        context.insert("user_settings", settings);
+    }
+    if let Some(impersonating) = config.impersonating {
+        context.insert("impersonating", impersonating);
+    }
+    // empty for anonymous visitors, so forms don't need to check it
+    context.insert("csrf_input", config.csrf_input.unwrap_or(""));
     templates.remake()?;
     Ok(templates.tera.render(template, &context)?)
 }
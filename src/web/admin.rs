@@ -0,0 +1,193 @@
+use actix_web::{http::header, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::{internal_error, meta, AppState};
+use crate::{
+    database::{
+        check,
+        ownership::{self, OwnershipAction, OwnershipRecord},
+        VersionedError,
+    },
+    templates,
+    utils::blocking,
+};
+
+// ===== Character ownership =====
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OwnershipForm {
+    action: String,
+    #[serde(default)]
+    new_owner: String,
+    #[serde(default)]
+    reason: String,
+    confirm: Option<String>,
+}
+
+impl OwnershipForm {
+    fn action(&self) -> Result<OwnershipAction, &'static str> {
+        Ok(match self.action.as_str() {
+            "release" => OwnershipAction::Release,
+            "reassign" => OwnershipAction::Reassign(
+                self.new_owner
+                    .trim()
+                    .parse()
+                    .map_err(|_| "New owner should be a Discord user id")?,
+            ),
+            "lock" => OwnershipAction::Lock,
+            "unlock" => OwnershipAction::Unlock,
+            _ => return Err("Unknown action"),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OwnershipPage {
+    char_id: u32,
+    owner: Option<u64>,
+    locked: bool,
+    last_change: Option<OwnershipRecord>,
+    pending: Option<OwnershipForm>,
+    error: Option<&'static str>,
+}
+
+pub async fn ownership(
+    path: web::Path<u32>,
+    data: web::Data<AppState>,
+    impersonating: meta::Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    render_ownership(data, path.into_inner(), None, None, impersonating).await
+}
+
+pub async fn change_ownership(
+    path: web::Path<u32>,
+    form: web::Form<OwnershipForm>,
+    data: web::Data<AppState>,
+    member: meta::Member,
+    impersonating: meta::Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let char_id = path.into_inner();
+    let form = form.into_inner();
+
+    let action = match form.action() {
+        Ok(action) => action,
+        Err(err) => {
+            return render_ownership(data, char_id, Some(form), Some(err), impersonating).await;
+        }
+    };
+    if form.reason.trim().is_empty() {
+        let error = Some("Reason is required");
+        return render_ownership(data, char_id, Some(form), error, impersonating).await;
+    }
+    if form.confirm.is_none() {
+        // Show the same page with the pending change and ask to confirm it
+        return render_ownership(data, char_id, Some(form), None, impersonating).await;
+    }
+
+    let admin_id = member.id;
+    let root = data.sled_db.root.clone();
+    let reason = form.reason.trim().to_owned();
+    let record =
+        blocking(move || ownership::change_ownership(&root, char_id, action, admin_id, reason))
+            .await
+            .map_err(internal_error)?;
+    println!("Ownership of {} changed: {:?}", char_id, record);
+
+    Ok(HttpResponse::SeeOther()
+        .append_header((
+            header::LOCATION,
+            format!("/admin/char/{}/ownership", char_id),
+        ))
+        .finish())
+}
+
+async fn render_ownership(
+    data: web::Data<AppState>,
+    char_id: u32,
+    pending: Option<OwnershipForm>,
+    error: Option<&'static str>,
+    impersonating: meta::Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let body = blocking(move || {
+        let root = &data.sled_db.root;
+        let page = OwnershipPage {
+            char_id,
+            owner: ownership::get_ownership(root, char_id)?,
+            locked: ownership::is_locked(root, char_id)?,
+            last_change: ownership::last_ownership_change(root, char_id)?,
+            pending,
+            error,
+        };
+        templates::render(
+            "admin_ownership.html",
+            &page,
+            impersonating.render_config(&data.config.host),
+        )
+        .map_err(AdminError::Template)
+    })
+    .await
+    .map_err(internal_error)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+// ===== Database check =====
+
+pub async fn check(
+    data: web::Data<AppState>,
+    impersonating: meta::Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    render_check(data, false, impersonating).await
+}
+
+pub async fn repair(
+    data: web::Data<AppState>,
+    impersonating: meta::Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    render_check(data, true, impersonating).await
+}
+
+async fn render_check(
+    data: web::Data<AppState>,
+    repair: bool,
+    impersonating: meta::Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let body = blocking(move || {
+        let report = check::check(&data.sled_db.root, repair)?;
+        templates::render(
+            "admin_check.html",
+            &report,
+            impersonating.render_config(&data.config.host),
+        )
+        .map_err(AdminError::Template)
+    })
+    .await
+    .map_err(internal_error)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+// ===== AdminError =====
+
+#[derive(Debug)]
+pub enum AdminError {
+    Versioned(VersionedError),
+    Template(templates::TemplatesError),
+    Blocking,
+}
+
+impl From<VersionedError> for AdminError {
+    fn from(err: VersionedError) -> Self {
+        AdminError::Versioned(err)
+    }
+}
+
+impl From<actix_web::error::BlockingError> for AdminError {
+    fn from(_err: actix_web::error::BlockingError) -> Self {
+        AdminError::Blocking
+    }
+}
@@ -1,22 +1,48 @@
-use std::io::Cursor;
+use std::{
+    io::Cursor,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
 
-use actix_web::{error::BlockingError, web, HttpResponse};
+use actix_multipart::{Multipart, MultipartError};
+use actix_web::{
+    error::BlockingError,
+    http::{
+        header::{self, EntityTag},
+        StatusCode,
+    },
+    web, HttpResponse,
+};
 use arrayvec::ArrayVec;
 use futures::{
     future::{err as fut_err, Either},
-    Future, FutureExt, TryFutureExt,
+    Future, FutureExt, TryFutureExt, TryStreamExt,
 };
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use lru::LruCache;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 
+use super::meta;
 use crate::{
     bridge,
-    database::{CharTrunk, Leaf, Root, VersionedError},
+    config::{Dither, ImageSlot},
+    database::{
+        moderation::{ImageStatus, ModerationState},
+        ArcSlice, CharTrunk, Leaf, LeafInfo, Root, VersionedError,
+    },
+    palette::{self, Palette},
     templates,
     utils::blocking,
 };
 
 // size of square image in pixels, 128 means 128x128
 const IMAGE_SIZE: u32 = 128;
+/// Bigger version stored along with every upload
+const PORTRAIT_SIZE: u32 = 256;
+/// Sizes that can be requested from `show`, others are generated from `IMAGE_SIZE` on demand
+const SIZES: [u32; 4] = [32, 64, IMAGE_SIZE, PORTRAIT_SIZE];
 const AUTH_LEN: usize = 12;
 const AUTH_HEX_LEN: usize = AUTH_LEN * 2;
 
@@ -24,6 +50,7 @@ const AUTH_HEX_LEN: usize = AUTH_LEN * 2;
 pub struct VersionSecret {
     ver: Option<u32>,
     secret: Option<u32>,
+    size: Option<u32>,
 }
 
 #[derive(Deserialize)]
@@ -35,12 +62,15 @@ pub struct Auth {
 
 pub type AuthVec = ArrayVec<u8, AUTH_LEN>;
 pub fn parse_auth(auth: &Auth) -> Option<(AuthVec, String)> {
-    let str: &str = auth.auth.as_ref()?.as_str();
+    parse_auth_hex(auth.auth.as_ref()?)
+}
+
+/// Authkey is sent to the game as 24 hex digits
+pub fn parse_auth_hex(str: &str) -> Option<(AuthVec, String)> {
     if str.len() != AUTH_HEX_LEN {
         return None;
     }
     let auth_string = str.to_uppercase();
-    dbg!(&auth_string);
     let mut arr = AuthVec::new();
     let mut cur = auth_string.as_str();
     while !cur.is_empty() {
@@ -60,21 +90,41 @@ pub fn parse_auth(auth: &Auth) -> Option<(AuthVec, String)> {
 #[derive(Debug, Serialize)]
 struct AvatarEditor {
     char_id: u32,
+    moderation: bool,
+    /// Uploads after the last approved one, newest first
+    statuses: Vec<(u32, ImageStatus)>,
 }
 
 pub async fn edit(
     path: web::Path<u32>,
     data: web::Data<super::AppState>,
+    impersonating: meta::Impersonating,
 ) -> actix_web::Result<HttpResponse> {
     let char_id = *path;
 
     let res = blocking(move || {
+        let mut statuses = data
+            .sled_db
+            .root
+            .trunk(char_id, None, CharTrunk::default())
+            .image_statuses()
+            .map_err(AvatarUploadError::SledVersioned)?;
+        if let Some(approved) = statuses
+            .iter()
+            .rposition(|(_ver, status)| status.state == ModerationState::Approved)
+        {
+            statuses.drain(..=approved);
+        }
+        statuses.reverse();
+        let editor = AvatarEditor {
+            char_id,
+            moderation: data.config.avatar.moderation,
+            statuses,
+        };
         templates::render(
             "edit_avatar.html",
-            &AvatarEditor { char_id },
-            templates::RenderConfig {
-                host: Some(&data.config.host),
-            },
+            &editor,
+            impersonating.render_config(&data.config.host),
         )
         .map_err(AvatarUploadError::Template)
     })
@@ -89,70 +139,400 @@ pub async fn edit(
     })
 }
 
+// ===== Avatar history =====
+
+#[derive(Debug, Serialize)]
+struct AvatarHistory {
+    char_id: u32,
+    versions: Vec<LeafInfo>,
+    /// Palette is loaded, so versions can be exported to FRM
+    frm: bool,
+}
+
+pub async fn history(
+    path: web::Path<u32>,
+    data: web::Data<super::AppState>,
+    impersonating: meta::Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let char_id = *path;
+
+    let res = blocking(move || {
+        let mut versions = data
+            .sled_db
+            .root
+            .trunk(char_id, None, CharTrunk::default())
+            .image_history()
+            .map_err(AvatarUploadError::SledVersioned)?;
+        versions.reverse();
+        templates::render(
+            "avatar_history.html",
+            &AvatarHistory {
+                char_id,
+                versions,
+                frm: data.palette.is_some(),
+            },
+            impersonating.render_config(&data.config.host),
+        )
+        .map_err(AvatarUploadError::Template)
+    })
+    .await;
+    Ok(match res {
+        Err(AvatarUploadError::Template(err)) => {
+            eprintln!("AvatarHistory template error: {:#?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+        Err(err) => HttpResponse::InternalServerError().body(format!("Error: {:?}", err)),
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+    })
+}
+
+/// Makes older version the newest one and sends it to the game
+pub async fn restore(
+    path: web::Path<(u32, u32)>,
+    data: web::Data<super::AppState>,
+) -> Result<HttpResponse, AvatarUploadError> {
+    let (char_id, ver) = path.into_inner();
+    let root = data.sled_db.root.clone();
+    let leaf = blocking(move || {
+        let trunk = root.trunk(char_id, None, CharTrunk::default());
+        let leaf = trunk.restore_image(ver)?;
+        if let Some(portrait) = trunk.get_image_size(ver, PORTRAIT_SIZE)? {
+            trunk.set_image_size(leaf.ver, PORTRAIT_SIZE, portrait.to_vec())?;
+        }
+        Ok(leaf)
+    })
+    .await
+    .map_err(AvatarUploadError::SledVersioned)?;
+    println!("Avatar {} of {} restored as {}", ver, char_id, leaf.ver);
+    update_char_leaf(data.bridge.get_sender(), char_id, leaf)?;
+
+    Ok(HttpResponse::SeeOther()
+        .append_header((
+            header::LOCATION,
+            format!("/char/{}/history/avatar", char_id),
+        ))
+        .finish())
+}
+
+/// Downloads the version reduced to the game palette as FRM image
+pub async fn export_frm(
+    path: web::Path<(u32, u32)>,
+    data: web::Data<super::AppState>,
+) -> Result<HttpResponse, AvatarUploadError> {
+    let (char_id, ver) = path.into_inner();
+    let frm = blocking(move || {
+        let palette = data.palette.as_ref().ok_or(AvatarUploadError::NoPalette)?;
+        let png = data
+            .sled_db
+            .root
+            .trunk(char_id, None, CharTrunk::default())
+            .get_image_version(ver)
+            .map_err(AvatarUploadError::SledVersioned)?
+            .ok_or(AvatarUploadError::SledVersioned(VersionedError::NotFound))?;
+        let image = image::load_from_memory_with_format(&png, ImageFormat::Png)
+            .map_err(AvatarUploadError::ImageLoad)?
+            .to_rgb8();
+        let indices = palette.quantize(&image, data.config.avatar.dither);
+        Ok(palette::write_frm(
+            image.width() as u16,
+            image.height() as u16,
+            &indices,
+        ))
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .append_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"avatar_{}_{}.frm\"", char_id, ver),
+        ))
+        .body(frm))
+}
+
 // ===== Upload avatar =====
 
+/// Max length of uploaded data, base64 data url or raw file
+pub const MAX_UPLOAD_LEN: usize = 4 * 1024 * 1024;
+/// Uploads with bigger dimensions are rejected before decoding
+const MAX_SOURCE_SIZE: u32 = 4096;
+const MIN_SOURCE_SIZE: u32 = 16;
+const FORMATS: [ImageFormat; 4] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::WebP,
+    ImageFormat::Gif,
+];
+
+/// Optional crop rectangle in pixels of uploaded image, or focal point in fractions of its size.
+/// Without both the image is cropped to square around its center.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub struct Fit {
+    x: Option<u32>,
+    y: Option<u32>,
+    w: Option<u32>,
+    h: Option<u32>,
+    fx: Option<f32>,
+    fy: Option<f32>,
+}
+
 pub fn upload(
     path: web::Path<u32>,
+    fit: web::Query<Fit>,
     data: web::Data<super::AppState>,
     payload: web::Bytes,
 ) -> impl Future<Output = Result<HttpResponse, AvatarUploadError>> {
     const MIN_LEN: usize = 16;
-    const MAX_LEN: usize = 128 * 1024;
-    const PREFIX_LEN: usize = 22;
-    const PREFIX: &[u8; PREFIX_LEN] = b"data:image/png;base64,";
 
-    if payload.len() <= PREFIX_LEN || !payload.starts_with(PREFIX) {
-        return Either::Left(fut_err(AvatarUploadError::DataUrl));
-    }
-
-    let data_len = payload.len() - PREFIX_LEN;
-    if !(MIN_LEN..=MAX_LEN).contains(&data_len) {
-        return Either::Left(fut_err(AvatarUploadError::DataLength(data_len)));
+    let (format, encoded) = match parse_data_url(&payload) {
+        Ok(parsed) => parsed,
+        Err(err) => return Either::Left(fut_err(err)),
+    };
+    if !(MIN_LEN..=MAX_UPLOAD_LEN).contains(&encoded.len()) {
+        return Either::Left(fut_err(AvatarUploadError::DataLength(encoded.len())));
     }
+    let offset = payload.len() - encoded.len();
 
     let char_id = *path;
+    let fit = *fit;
     let root = data.sled_db.root.clone();
     let sender = data.bridge.get_sender();
+    let options = SaveOptions::new(&data);
+    let moderation = options.moderation;
     Either::Right(
         blocking(move || {
-            let data = &payload[PREFIX_LEN..];
-            save_image(&root, char_id, data)
+            let decoded = base64::decode_config(&payload[offset..], base64::STANDARD)
+                .map_err(AvatarUploadError::Base64)?;
+            save_image(
+                &root,
+                char_id,
+                &decoded,
+                Some(format),
+                Target::Avatar(fit),
+                &options,
+            )
+        })
+        .map(move |res| {
+            res.and_then(|leaf| {
+                if moderation {
+                    // published after approval
+                    Ok(())
+                } else {
+                    update_char_leaf(sender, char_id, leaf)
+                }
+            })
         })
-        .map(move |res| res.and_then(|leaf| update_char_leaf(sender, char_id, leaf)))
         .map_ok(|_| HttpResponse::NoContent().finish()),
     )
 }
 
-fn save_image(root: &Root, char_id: u32, data: &[u8]) -> Result<Leaf<()>, AvatarUploadError> {
-    use image::{DynamicImage, ImageFormat};
+#[derive(Serialize)]
+struct Uploaded {
+    ver: u32,
+    pending: bool,
+}
+
+/// Takes image from `avatar` field of multipart form, format is sniffed from the content
+pub async fn upload_multipart(
+    path: web::Path<u32>,
+    fit: web::Query<Fit>,
+    data: web::Data<super::AppState>,
+    payload: Multipart,
+) -> Result<HttpResponse, AvatarUploadError> {
+    let file = read_file(payload, "avatar", MAX_UPLOAD_LEN).await?;
+
+    let format = match image::guess_format(&file) {
+        Ok(format) if FORMATS.contains(&format) => format,
+        Ok(format) => return Err(AvatarUploadError::ImageFormat(format!("{:?}", format))),
+        Err(_) => return Err(AvatarUploadError::ImageFormat("unknown".into())),
+    };
+
+    let char_id = *path;
+    let fit = *fit;
+    let root = data.sled_db.root.clone();
+    let sender = data.bridge.get_sender();
+    let options = SaveOptions::new(&data);
+    let moderation = options.moderation;
+    let leaf = blocking(move || {
+        save_image(
+            &root,
+            char_id,
+            &file,
+            Some(format),
+            Target::Avatar(fit),
+            &options,
+        )
+    })
+    .await?;
+    let ver = leaf.ver;
+    if !moderation {
+        update_char_leaf(sender, char_id, leaf)?;
+    }
+    Ok(HttpResponse::Ok().json(Uploaded {
+        ver,
+        pending: moderation,
+    }))
+}
+
+/// Reads the first field with `name`, the size is checked while it's streamed
+pub(super) async fn read_file(
+    mut payload: Multipart,
+    name: &str,
+    max_len: usize,
+) -> Result<Vec<u8>, AvatarUploadError> {
+    while let Some(mut field) = payload
+        .try_next()
+        .await
+        .map_err(AvatarUploadError::Multipart)?
+    {
+        if field.name() != name {
+            continue;
+        }
+        let mut buf = Vec::new();
+        while let Some(chunk) = field
+            .try_next()
+            .await
+            .map_err(AvatarUploadError::Multipart)?
+        {
+            if buf.len() + chunk.len() > max_len {
+                return Err(AvatarUploadError::TooLarge);
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        return Ok(buf);
+    }
+    Err(AvatarUploadError::NoFile)
+}
+
+// Splits `data:image/<type>;base64,<data>` into image format and data
+fn parse_data_url(payload: &[u8]) -> Result<(ImageFormat, &[u8]), AvatarUploadError> {
+    const PREFIX: &[u8] = b"data:image/";
+    const BASE64: &[u8] = b";base64,";
+    const MAX_MIME_LEN: usize = 16;
+
+    let rest = payload
+        .strip_prefix(PREFIX)
+        .ok_or(AvatarUploadError::DataUrl)?;
+    let mime_len = rest
+        .iter()
+        .take(MAX_MIME_LEN)
+        .position(|&byte| byte == b';')
+        .ok_or(AvatarUploadError::DataUrl)?;
+    let (mime, rest) = rest.split_at(mime_len);
+    let encoded = rest
+        .strip_prefix(BASE64)
+        .ok_or(AvatarUploadError::DataUrl)?;
+    let mime = String::from_utf8_lossy(mime);
+    let format = image_format(&mime).ok_or(AvatarUploadError::ImageFormat(mime.into()))?;
+    Ok((format, encoded))
+}
+
+pub(super) fn image_format(subtype: &str) -> Option<ImageFormat> {
+    Some(match subtype {
+        "png" => ImageFormat::Png,
+        "jpeg" | "jpg" => ImageFormat::Jpeg,
+        "webp" => ImageFormat::WebP,
+        "gif" => ImageFormat::Gif,
+        _ => return None,
+    })
+}
+
+/// Upload processing settings taken from the config
+pub(super) struct SaveOptions {
+    /// New version is marked as pending
+    moderation: bool,
+    quantize: Option<(Arc<Palette>, Dither)>,
+}
+
+impl SaveOptions {
+    pub(super) fn new(data: &super::AppState) -> Self {
+        let config = &data.config.avatar;
+        SaveOptions {
+            moderation: config.moderation,
+            quantize: data
+                .palette
+                .clone()
+                .filter(|_| config.quantize)
+                .map(|palette| (palette, config.dither)),
+        }
+    }
+}
+
+/// Where the upload is stored and how it is fitted
+pub(super) enum Target<'a> {
+    /// Square of `IMAGE_SIZE` stored as RGB PNG along with `PORTRAIT_SIZE` version
+    /// made from the same source
+    Avatar(Fit),
+    /// Exactly the slot dimensions, transparency is kept
+    Slot(&'a ImageSlot),
+}
 
+/// Decodes uploaded image, fits it to the target and stores as a new version,
+/// pending if moderation is enabled.
+/// If `format` is `None` it's guessed from the data, animated images are reduced to the first frame.
+pub(super) fn save_image(
+    root: &Root,
+    char_id: u32,
+    data: &[u8],
+    format: Option<ImageFormat>,
+    target: Target,
+    options: &SaveOptions,
+) -> Result<Leaf<()>, AvatarUploadError> {
     let instant = std::time::Instant::now();
-    let decoded =
-        base64::decode_config(data, base64::STANDARD).map_err(AvatarUploadError::Base64)?;
-    println!("Decoded in {:?}", instant.elapsed());
-    let instant2 = std::time::Instant::now();
-    let image = image::load_from_memory_with_format(&decoded, ImageFormat::Png)
+    let format = match format {
+        Some(format) => format,
+        None => image::guess_format(data).map_err(AvatarUploadError::ImageLoad)?,
+    };
+    if !FORMATS.contains(&format) {
+        return Err(AvatarUploadError::ImageFormat(format!("{:?}", format)));
+    }
+
+    let (width, height) = image::io::Reader::with_format(Cursor::new(data), format)
+        .into_dimensions()
         .map_err(AvatarUploadError::ImageLoad)?;
-    println!("Loaded in {:?}", instant2.elapsed());
-    let instant2 = std::time::Instant::now();
-    if image.width() != IMAGE_SIZE || image.height() != IMAGE_SIZE {
-        return Err(AvatarUploadError::ImageSize(image.width(), image.height()));
+    if width > MAX_SOURCE_SIZE || height > MAX_SOURCE_SIZE {
+        return Err(AvatarUploadError::ImageSize(width, height));
     }
-    let new_image = DynamicImage::ImageRgb8(image.to_rgb8());
 
-    let mut buffer = decoded;
-    buffer.clear();
-    let mut cursor = Cursor::new(buffer);
-    new_image
-        .write_to(&mut cursor, ImageFormat::Png)
-        .map_err(AvatarUploadError::ImageWrite)?;
-    println!("Writed in {:?}", instant2.elapsed());
+    let image =
+        image::load_from_memory_with_format(data, format).map_err(AvatarUploadError::ImageLoad)?;
+    println!("Loaded in {:?}", instant.elapsed());
+    let instant2 = std::time::Instant::now();
+    let reduce = |image: DynamicImage| match &options.quantize {
+        Some((palette, dither)) => quantize(&image, palette, *dither),
+        None => image,
+    };
+    let (bark, image, portrait) = match target {
+        Target::Avatar(fit) => {
+            let square = crop_square(image, fit)?;
+            let image = reduce(resize(&square, IMAGE_SIZE));
+            let portrait = reduce(resize(&square, PORTRAIT_SIZE));
+            (
+                CharTrunk::default(),
+                encode_png(&image)?,
+                Some(encode_png(&portrait)?),
+            )
+        }
+        Target::Slot(slot) => {
+            let image = reduce(super::image::fit_to_slot(image, slot));
+            (CharTrunk::slot(&slot.name), encode_png_rgba(&image)?, None)
+        }
+    };
+    println!("Fitted and written in {:?}", instant2.elapsed());
     let instant2 = std::time::Instant::now();
 
-    let leaf = root
-        .trunk(char_id, None, CharTrunk::default())
-        .set_image(cursor.into_inner())
-        .map_err(AvatarUploadError::SledVersioned)?;
+    let trunk = root.trunk(char_id, None, bark);
+    let leaf = if options.moderation {
+        trunk.set_pending_image(image)
+    } else {
+        trunk.set_image(image)
+    }
+    .map_err(AvatarUploadError::SledVersioned)?;
+    if let Some(portrait) = portrait {
+        trunk
+            .set_image_size(leaf.ver, PORTRAIT_SIZE, portrait)
+            .map_err(AvatarUploadError::SledVersioned)?;
+    }
     println!("Saved to db in {:?}", instant2.elapsed());
 
     println!("Fully saved in {:?}", instant.elapsed());
@@ -160,7 +540,114 @@ fn save_image(root: &Root, char_id: u32, data: &[u8]) -> Result<Leaf<()>, Avatar
     Ok(leaf)
 }
 
-fn update_char_leaf(
+/// Crops the image to square, see `Fit`
+fn crop_square(image: DynamicImage, fit: Fit) -> Result<DynamicImage, AvatarUploadError> {
+    let (width, height) = (image.width(), image.height());
+    if width < MIN_SOURCE_SIZE || height < MIN_SOURCE_SIZE {
+        return Err(AvatarUploadError::ImageSize(width, height));
+    }
+
+    let (x, y, w, h) = match (fit.x, fit.y, fit.w, fit.h) {
+        (Some(x), Some(y), Some(w), Some(h)) => {
+            let fits = w >= MIN_SOURCE_SIZE
+                && h >= MIN_SOURCE_SIZE
+                && x.checked_add(w).map_or(false, |right| right <= width)
+                && y.checked_add(h).map_or(false, |bottom| bottom <= height);
+            if !fits {
+                return Err(AvatarUploadError::Crop);
+            }
+            (x, y, w, h)
+        }
+        (None, None, None, None) => (0, 0, width, height),
+        _ => return Err(AvatarUploadError::Crop),
+    };
+
+    // square inside of the rectangle, as close to the focal point as possible
+    let side = w.min(h);
+    let focus = |fraction: Option<f32>, len: u32| {
+        let fraction = fraction.unwrap_or(0.5).clamp(0.0, 1.0);
+        let center = (fraction * len as f32) as u32;
+        center.saturating_sub(side / 2).min(len - side)
+    };
+    Ok(image.crop_imm(x + focus(fit.fx, w), y + focus(fit.fy, h), side, side))
+}
+
+fn resize(square: &DynamicImage, size: u32) -> DynamicImage {
+    if square.width() == size {
+        square.clone()
+    } else {
+        square.resize_exact(size, size, FilterType::Lanczos3)
+    }
+}
+
+/// Colors are reduced to the palette, alpha channel is kept as is
+fn quantize(image: &DynamicImage, palette: &Palette, dither: Dither) -> DynamicImage {
+    let rgb = image.to_rgb8();
+    let indices = palette.quantize(&rgb, dither);
+    let quantized = palette.to_image(rgb.width(), rgb.height(), &indices);
+    if !image.color().has_alpha() {
+        return DynamicImage::ImageRgb8(quantized);
+    }
+    let mut rgba = image.to_rgba8();
+    for (pixel, color) in rgba.pixels_mut().zip(quantized.pixels()) {
+        pixel.0[..3].copy_from_slice(&color.0);
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, AvatarUploadError> {
+    write_png(&DynamicImage::ImageRgb8(image.to_rgb8()))
+}
+
+fn encode_png_rgba(image: &DynamicImage) -> Result<Vec<u8>, AvatarUploadError> {
+    write_png(&DynamicImage::ImageRgba8(image.to_rgba8()))
+}
+
+fn write_png(image: &DynamicImage) -> Result<Vec<u8>, AvatarUploadError> {
+    let mut cursor = Cursor::new(Vec::new());
+    image
+        .write_to(&mut cursor, ImageFormat::Png)
+        .map_err(AvatarUploadError::ImageWrite)?;
+    Ok(cursor.into_inner())
+}
+
+/// Returns image of the version, scaled ones are generated once and stored.
+/// `None` size means the image as it was stored on upload.
+fn load_image(
+    root: &Root,
+    char_id: u32,
+    bark: CharTrunk,
+    ver: u32,
+    size: Option<u32>,
+) -> Result<ArcSlice, AvatarUploadError> {
+    let trunk = root.trunk(char_id, None, bark);
+    if let Some(size) = size {
+        if let Some(data) = trunk
+            .get_image_size(ver, size)
+            .map_err(AvatarUploadError::SledVersioned)?
+        {
+            return Ok(data);
+        }
+    }
+    let original = trunk
+        .get_image_version(ver)
+        .map_err(AvatarUploadError::SledVersioned)?
+        .ok_or(AvatarUploadError::SledVersioned(VersionedError::NotFound))?;
+    let size = match size {
+        Some(size) => size,
+        None => return Ok(original),
+    };
+
+    let image = image::load_from_memory_with_format(&original, ImageFormat::Png)
+        .map_err(AvatarUploadError::ImageLoad)?;
+    let data = encode_png(&resize(&image, size))?;
+    trunk
+        .set_image_size(ver, size, data.clone())
+        .map_err(AvatarUploadError::SledVersioned)?;
+    Ok(data.into())
+}
+
+pub(super) fn update_char_leaf(
     sender: Option<bridge::MsgOutSender>,
     id: u32,
     leaf: Leaf<()>,
@@ -182,35 +669,166 @@ fn update_char_leaf(
 
 // ===== Show avatar =====
 
+/// Image branch, character id, version and size
+type CacheKey = (String, u32, u32, Option<u32>);
+
+/// Recently served images of all slots
+pub struct AvatarCache(Option<Mutex<LruCache<CacheKey, ArcSlice>>>);
+
+impl AvatarCache {
+    /// Zero capacity disables the cache
+    pub fn new(capacity: usize) -> Self {
+        AvatarCache(NonZeroUsize::new(capacity).map(|capacity| Mutex::new(LruCache::new(capacity))))
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<ArcSlice> {
+        self.0.as_ref()?.lock().get(key).cloned()
+    }
+
+    fn put(&self, key: CacheKey, data: ArcSlice) {
+        if let Some(cache) = &self.0 {
+            cache.lock().put(key, data);
+        }
+    }
+}
+
+struct Shown {
+    ver: u32,
+    /// Only approved versions are cached for long, others may still be rejected
+    approved: bool,
+    etag: EntityTag,
+    written: Option<u64>,
+    /// `None` if client already has this version
+    data: Option<ArcSlice>,
+}
+
 pub async fn show(
     path: web::Path<u32>,
     query: web::Query<VersionSecret>,
+    if_none_match: Option<web::Header<header::IfNoneMatch>>,
+    data: web::Data<super::AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let VersionSecret { ver, secret, size } = *query;
+
+    let size = size.unwrap_or(IMAGE_SIZE);
+    if !SIZES.contains(&size) {
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+    let size = Some(size).filter(|size| *size != IMAGE_SIZE);
+    let bark = CharTrunk::default();
+    serve_image(data, *path, bark, ver, secret, size, if_none_match).await
+}
+
+/// Approved versions never change, so responses for explicit `ver` are cached forever,
+/// versions without approval are cached privately for a few minutes,
+/// the latest version is revalidated with `ETag` on every request.
+pub(super) async fn serve_image(
     data: web::Data<super::AppState>,
+    char_id: u32,
+    bark: CharTrunk,
+    ver: Option<u32>,
+    secret: Option<u32>,
+    size: Option<u32>,
+    if_none_match: Option<web::Header<header::IfNoneMatch>>,
 ) -> actix_web::Result<HttpResponse> {
-    let VersionSecret { ver, secret } = *query;
+    const YEAR: u32 = 365 * 24 * 60 * 60;
+    const UNAPPROVED_MAX_AGE: u32 = 5 * 60;
 
     if secret.is_none() {
         return Ok(HttpResponse::Forbidden().finish());
     }
 
-    let root = data.sled_db.root.clone();
     let res = blocking(move || {
         let instant = std::time::Instant::now();
-        let leaf = root
-            .trunk(*path, ver, CharTrunk::default())
-            .get_image(secret)?;
+        let root = &data.sled_db.root;
+        let branch = bark.image_branch().to_owned();
+        let trunk = root.trunk(char_id, ver, bark.clone());
+        let (found, _secret) = trunk
+            .published_version(secret)
+            .map_err(AvatarUploadError::SledVersioned)?;
+        let approved = trunk
+            .get_image_status(found)
+            .map_err(AvatarUploadError::SledVersioned)?
+            .map_or(false, |status| status.state == ModerationState::Approved);
+        let etag = EntityTag::new_strong(format!(
+            "{}-{:x}-{}-{}-{}",
+            branch,
+            char_id,
+            found,
+            size.unwrap_or(0),
+            if approved { "approved" } else { "unmoderated" }
+        ));
+        let written = trunk
+            .get_image_time(found)
+            .map_err(AvatarUploadError::SledVersioned)?;
+
+        let not_modified = match if_none_match.as_deref() {
+            Some(header::IfNoneMatch::Any) => true,
+            Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+            None => false,
+        };
+        let data = if not_modified {
+            None
+        } else {
+            let key = (branch, char_id, found, size);
+            Some(match data.avatar_cache.get(&key) {
+                Some(image) => image,
+                None => {
+                    let image = load_image(root, char_id, bark, found, size)?;
+                    data.avatar_cache.put(key, image.clone());
+                    image
+                }
+            })
+        };
         println!("Getting image, completed in {:?}", instant.elapsed());
-        Ok(leaf)
+        Ok(Shown {
+            ver: found,
+            approved,
+            etag,
+            written,
+            data,
+        })
     })
     .await;
-    Ok(match res {
-        Ok(image) => HttpResponse::Ok()
-            .append_header(("q-ver", image.ver as u64))
-            .append_header(("q-length", image.data.len()))
+
+    let shown = match res {
+        Ok(shown) => shown,
+        Err(AvatarUploadError::SledVersioned(VersionedError::NotFound)) => {
+            return Ok(HttpResponse::NotFound().finish())
+        }
+        Err(err) => {
+            return Ok(HttpResponse::InternalServerError().body(format!("Error: {:?}", err)))
+        }
+    };
+
+    let mut response = match shown.data {
+        Some(_) => HttpResponse::Ok(),
+        None => HttpResponse::NotModified(),
+    };
+    response.insert_header(header::ETag(shown.etag));
+    if let Some(written) = shown.written {
+        let written = UNIX_EPOCH + Duration::from_secs(written);
+        response.insert_header(header::LastModified(written.into()));
+    }
+    response.insert_header(header::CacheControl(match (ver, shown.approved) {
+        (Some(_), true) => vec![
+            header::CacheDirective::Public,
+            header::CacheDirective::MaxAge(YEAR),
+            header::CacheDirective::Extension("immutable".into(), None),
+        ],
+        (Some(_), false) => vec![
+            header::CacheDirective::Private,
+            header::CacheDirective::MaxAge(UNAPPROVED_MAX_AGE),
+        ],
+        (None, _) => vec![header::CacheDirective::NoCache],
+    }));
+    Ok(match shown.data {
+        Some(image) => response
+            .append_header(("q-ver", shown.ver as u64))
+            .append_header(("q-length", image.len()))
             .content_type("image/png")
-            .body(bytes::Bytes::copy_from_slice(image.data.as_ref())),
-        Err(VersionedError::NotFound) => HttpResponse::NotFound().finish(),
-        Err(err) => HttpResponse::InternalServerError().body(format!("Error: {:?}", err)),
+            .body(bytes::Bytes::copy_from_slice(image.as_ref())),
+        None => response.finish(),
     })
 }
 
@@ -220,16 +838,55 @@ pub async fn show(
 pub enum AvatarUploadError {
     DataUrl,
     DataLength(usize),
+    Multipart(MultipartError),
+    NoFile,
+    TooLarge,
+    NoPalette,
+    UnknownSlot,
     Blocking,
     Base64(base64::DecodeError),
     ImageLoad(image::ImageError),
+    ImageFormat(String),
     ImageSize(u32, u32),
+    Crop,
     ImageWrite(image::ImageError),
     SledVersioned(VersionedError),
     FuturesSyncSend,
     Template(templates::TemplatesError),
 }
 
+impl AvatarUploadError {
+    fn code(&self) -> &'static str {
+        match self {
+            AvatarUploadError::DataUrl => "data_url",
+            AvatarUploadError::DataLength(_) => "data_length",
+            AvatarUploadError::Multipart(_) => "multipart",
+            AvatarUploadError::NoFile => "no_file",
+            AvatarUploadError::TooLarge => "too_large",
+            AvatarUploadError::NoPalette => "no_palette",
+            AvatarUploadError::UnknownSlot => "unknown_slot",
+            AvatarUploadError::Base64(_) => "base64",
+            AvatarUploadError::ImageLoad(_) => "image_load",
+            AvatarUploadError::ImageFormat(_) => "image_format",
+            AvatarUploadError::ImageSize(..) => "image_size",
+            AvatarUploadError::Crop => "crop",
+            AvatarUploadError::SledVersioned(VersionedError::NotFound) => "not_found",
+            AvatarUploadError::SledVersioned(VersionedError::AccessDenied) => "access_denied",
+            AvatarUploadError::Blocking
+            | AvatarUploadError::ImageWrite(_)
+            | AvatarUploadError::SledVersioned(_)
+            | AvatarUploadError::FuturesSyncSend
+            | AvatarUploadError::Template(_) => "internal",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
+}
+
 impl From<BlockingError> for AvatarUploadError {
     fn from(_err: BlockingError) -> Self {
         AvatarUploadError::Blocking
@@ -243,27 +900,37 @@ impl std::fmt::Display for AvatarUploadError {
 }
 
 impl actix_web::error::ResponseError for AvatarUploadError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AvatarUploadError::DataUrl
+            | AvatarUploadError::DataLength(_)
+            | AvatarUploadError::Multipart(_)
+            | AvatarUploadError::NoFile
+            | AvatarUploadError::Base64(_)
+            | AvatarUploadError::ImageLoad(_)
+            | AvatarUploadError::ImageSize(..)
+            | AvatarUploadError::Crop => StatusCode::BAD_REQUEST,
+            AvatarUploadError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AvatarUploadError::NoPalette | AvatarUploadError::UnknownSlot => StatusCode::NOT_FOUND,
+            AvatarUploadError::ImageFormat(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AvatarUploadError::SledVersioned(VersionedError::NotFound) => StatusCode::NOT_FOUND,
+            AvatarUploadError::SledVersioned(VersionedError::AccessDenied) => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
     fn error_response(&self) -> HttpResponse {
         log::warn!("{:?}", self);
 
-        use actix_web::http::StatusCode;
-        HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+        let message = if self.status_code().is_server_error() {
+            // don't leak internals
+            "Internal server error".into()
+        } else {
+            self.to_string()
+        };
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.code(),
+            message,
+        })
     }
-    // TODO: Investigate error rendering
-    /*
-    /// Constructs an error response
-    fn render_response(&self) -> HttpResponse {
-
-
-        use actix_web::{http::{header, StatusCode}, body::Body};
-
-        let mut resp = self.error_response();
-        let mut buf = web::BytesMut::new();
-        let _ = write!(Writer(&mut buf), "{}", self);
-        resp.headers_mut().insert(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_static("text/plain"),
-        );
-        resp.set_body(Body::from(buf))
-    }*/
 }
@@ -1,11 +1,41 @@
 use actix_web::{web, Error, HttpResponse};
+use serde::Serialize;
 
-use crate::bridge::MsgOut;
+use super::{internal_error, meta};
+use crate::{bridge::MsgOut, templates};
+
+#[derive(Serialize)]
+struct StartGamePage {
+    char_id: u32,
+}
+
+/// Link from the game only shows a form, the game is started by posting it
+pub async fn start_game_form(
+    path: web::Path<u32>,
+    data: web::Data<super::AppState>,
+    impersonating: meta::Impersonating,
+) -> Result<HttpResponse, Error> {
+    let body = templates::render(
+        "start_game.html",
+        &StartGamePage {
+            char_id: path.into_inner(),
+        },
+        impersonating.render_config(&data.config.host),
+    )
+    .map_err(internal_error)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
 
 pub async fn start_game(
     path: web::Path<u32>,
     data: web::Data<super::AppState>,
+    impersonating: meta::Impersonating,
 ) -> Result<HttpResponse, Error> {
+    if impersonating.label().is_some() {
+        return Err(meta::access_denied("Read only while impersonating")().into());
+    }
     Ok(
         if data
             .bridge
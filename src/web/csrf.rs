@@ -0,0 +1,197 @@
+use std::rc::Rc;
+
+use actix_service::{Service, Transform};
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    body::MessageBody,
+    cookie::{Cookie, SameSite},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    web,
+};
+use futures::{
+    future::{self as fut, LocalBoxFuture},
+    FutureExt,
+};
+use serde::Deserialize;
+
+use super::meta;
+use crate::database::new_secret;
+
+const CSRF_SESSION_NAME: &str = "csrf_token";
+/// Readable by scripts, `base.html` copies it into XHR and fetch headers
+const CSRF_COOKIE_NAME: &str = "csrf_token";
+const CSRF_HEADER: &str = "x-csrf-token";
+const CSRF_FIELD: &str = "csrf_token";
+
+#[derive(Deserialize)]
+struct CsrfField {
+    csrf_token: String,
+}
+
+fn existing_token(session: &Session) -> Option<String> {
+    match session.get(CSRF_SESSION_NAME) {
+        Ok(token) => token,
+        Err(err) => {
+            eprintln!("csrf_token error: {:?}", err);
+            session.remove(CSRF_SESSION_NAME);
+            None
+        }
+    }
+}
+
+/// Token of the session, created on first use
+pub fn csrf_token(session: &Session) -> String {
+    existing_token(session).unwrap_or_else(|| {
+        let token = new_secret("");
+        if let Err(err) = session.insert(CSRF_SESSION_NAME, &token) {
+            eprintln!("Can't store csrf_token: {:?}", err);
+        }
+        token
+    })
+}
+
+/// Hidden form field of the session, put into every POST form
+pub fn csrf_input(session: &Session) -> String {
+    format!(
+        r#"<input type="hidden" name="{}" value="{}">"#,
+        CSRF_FIELD,
+        csrf_token(session)
+    )
+}
+
+/// Form field for sessions that hold something, anonymous visitors get no session
+pub fn session_csrf_input(session: &Session) -> Option<String> {
+    if existing_token(session).is_none() && session.entries().is_empty() {
+        return None;
+    }
+    Some(csrf_input(session))
+}
+
+fn is_safe(method: &Method) -> bool {
+    method == &Method::GET || method == &Method::HEAD || method == &Method::OPTIONS
+}
+
+/// Invalid token is an error, not a reason to look at the session instead
+async fn by_token(req: &ServiceRequest) -> Result<bool, actix_web::Error> {
+    if !req.headers().contains_key(header::AUTHORIZATION) {
+        return Ok(false);
+    }
+    let member = meta::extract_member(req.request()).await?;
+    Ok(member.map_or(false, |member| member.by_token))
+}
+
+fn is_form(req: &ServiceRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| {
+            value.starts_with("application/x-www-form-urlencoded")
+        })
+}
+
+/// Synchronizer token: every state-changing request should send token of the session back
+/// in `X-CSRF-Token` header or in `csrf_token` field of url-encoded form.
+/// Requests authenticated with API token aren't sent by browsers on their own and are let through,
+/// handlers take the user from the token then.
+/// Token is created for sessions that already hold something, anonymous visitors get no session.
+pub struct Csrf {
+    secure: bool,
+}
+
+impl Csrf {
+    pub fn new(secure: bool) -> Self {
+        Csrf { secure }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Csrf
+where
+    S: 'static + Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Error = actix_web::Error;
+    type Future = fut::Ready<Result<Self::Transform, Self::InitError>>;
+    type InitError = ();
+    type Response = ServiceResponse<B>;
+    type Transform = CsrfMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        fut::ok(CsrfMiddleware {
+            service: Rc::new(service),
+            secure: self.secure,
+        })
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+    secure: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: 'static + Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+    type Response = ServiceResponse<B>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let secure = self.secure;
+        async move {
+            if !is_safe(req.method()) && !by_token(&req).await? {
+                let submitted = match req.headers().get(CSRF_HEADER) {
+                    Some(value) => value.to_str().ok().map(String::from),
+                    None if is_form(&req) => {
+                        let body = req.extract::<web::Bytes>().await?;
+                        let field = std::str::from_utf8(&body).ok().and_then(|query| {
+                            web::Query::<CsrfField>::from_query(query)
+                                .ok()
+                                .map(|field| field.into_inner().csrf_token)
+                        });
+                        // handler reads the form again
+                        let (_, mut payload) = actix_http::h1::Payload::create(true);
+                        payload.unread_data(body);
+                        req.set_payload(payload.into());
+                        field
+                    }
+                    None => None,
+                };
+                let token = existing_token(&req.get_session());
+                if submitted.is_none() || submitted != token {
+                    return Err(meta::access_denied("Wrong or missing CSRF token")().into());
+                }
+            }
+
+            let mut res = service.call(req).await?;
+            let session = res.request().get_session();
+            let token = match existing_token(&session) {
+                Some(token) => Some(token),
+                None if !session.entries().is_empty() => Some(csrf_token(&session)),
+                None => None,
+            };
+            let cookie_set = match (&token, res.request().cookie(CSRF_COOKIE_NAME)) {
+                (Some(token), Some(cookie)) => cookie.value() == token,
+                (None, _) => true,
+                (Some(_), None) => false,
+            };
+            if let (Some(token), false) = (token, cookie_set) {
+                let cookie = Cookie::build(CSRF_COOKIE_NAME, token)
+                    .path("/")
+                    .same_site(SameSite::Strict)
+                    .secure(secure)
+                    .finish();
+                res.response_mut().add_cookie(&cookie)?;
+            }
+            Ok(res)
+        }
+        .boxed_local()
+    }
+}
@@ -1,37 +1,349 @@
-use std::{borrow::Cow, net::Ipv4Addr, time::Duration};
+use std::{borrow::Cow, cmp::Ordering, net::Ipv4Addr, time::Duration};
 
 use fo_clients_db::{fix_encoding::os_str_debug, ClientRecord};
 use fo_defines::CritterParam;
 use fo_defines_fo4rp::{fos, param::Param};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use super::{web, AppState, HttpResponse};
+use actix_web::{error::BlockingError, http::StatusCode};
+
+use super::{meta, web, AppState, HttpResponse};
 use crate::{
-    config::Host,
-    database::{ownership::get_ownership, Root},
+    database::{
+        image_characters, ownership::get_ownership, unix_now, CharTrunk, Root, VersionedError,
+    },
     templates,
 };
 
-pub async fn clients(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
-    let mrhandy = data.mrhandy.as_ref().expect("Discord config");
-    let members = mrhandy.clone_members().await;
+/// Filters, sorting and page of `/api/v1/clients`, the HTML list uses the same query
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ClientsQuery {
+    /// Part of the character name, Cyrillic and Latin lookalikes are the same letter
+    #[serde(default)]
+    name: String,
+    /// Address like `1.2.3.4` or network like `1.2.3.0/24`
+    #[serde(default, deserialize_with = "empty_as_none")]
+    ip: Option<Ipv4Net>,
+    /// Condition like `ALIVE` or `DEAD`, any if empty
+    #[serde(default)]
+    cond: String,
+    #[serde(default)]
+    hide_dead: bool,
+    /// Only clients seen during the last minutes
+    #[serde(default)]
+    online: bool,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    map_id: Option<u32>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    map_pid: Option<u16>,
+    /// Game mode like `ADVENTURE`, any if empty
+    #[serde(default)]
+    gamemode: String,
+    /// At least this `ST_ACCESS_LEVEL`
+    #[serde(default, deserialize_with = "empty_as_none")]
+    access_level: Option<i32>,
+    /// Discord id, name or nickname of the owner
+    #[serde(default)]
+    owner: String,
+    #[serde(default)]
+    sort: ClientsSort,
+    #[serde(default)]
+    order: SortOrder,
+    /// Starts from 1
+    #[serde(default = "ClientsQuery::first_page")]
+    page: usize,
+    #[serde(default = "ClientsQuery::default_per_page")]
+    per_page: usize,
+}
+
+const MAX_PER_PAGE: usize = 1000;
+
+impl Default for ClientsQuery {
+    fn default() -> Self {
+        ClientsQuery {
+            name: String::new(),
+            ip: None,
+            cond: String::new(),
+            hide_dead: false,
+            online: false,
+            map_id: None,
+            map_pid: None,
+            gamemode: String::new(),
+            access_level: None,
+            owner: String::new(),
+            sort: ClientsSort::default(),
+            order: SortOrder::default(),
+            page: Self::first_page(),
+            per_page: Self::default_per_page(),
+        }
+    }
+}
+
+impl ClientsQuery {
+    fn first_page() -> usize {
+        1
+    }
+
+    fn default_per_page() -> usize {
+        100
+    }
+
+    fn matches(&self, row: &ClientRow) -> bool {
+        if self.online && !row.last_seen.as_ref().map_or(false, |seen| seen.1) {
+            return false;
+        }
+        let name = self.name.trim();
+        if !contains_lookalike(row.name, name) {
+            return false;
+        }
+        let info = match &row.info {
+            Some(info) => info,
+            None => {
+                return self.ip.is_none()
+                    && self.cond.is_empty()
+                    && self.map_id.is_none()
+                    && self.map_pid.is_none()
+                    && self.gamemode.is_empty()
+                    && self.access_level.is_none()
+                    && self.owner.trim().is_empty()
+            }
+        };
+        let cond = self.cond.trim();
+        let gamemode = self.gamemode.trim();
+        let owner = self.owner.trim();
+        self.ip
+            .map_or(true, |net| info.ip.iter().any(|ip| net.contains(*ip)))
+            && (cond.is_empty() || info.cond.eq_ignore_ascii_case(cond))
+            && !(self.hide_dead && info.cond == "DEAD")
+            && self.map_id.map_or(true, |map_id| info.map_id == map_id)
+            && self.map_pid.map_or(true, |map_pid| info.map_pid == map_pid)
+            && (gamemode.is_empty() || info.gamemode.eq_ignore_ascii_case(gamemode))
+            && self
+                .access_level
+                .map_or(true, |level| info.st_access_level >= level)
+            && (owner.is_empty()
+                || info
+                    .discord
+                    .as_ref()
+                    .map_or(false, |discord| discord.matches(owner)))
+    }
+}
+
+/// Cyrillic and Latin letters that look the same in both cases, in lowercase
+const LOOKALIKES: [(char, char); 12] = [
+    ('а', 'a'),
+    ('е', 'e'),
+    ('ё', 'e'),
+    ('к', 'k'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('с', 'c'),
+    ('у', 'y'),
+    ('х', 'x'),
+    ('і', 'i'),
+    ('ј', 'j'),
+    ('ѕ', 's'),
+];
+/// Cyrillic capitals that look like Latin ones, while their small letters don't
+const CAPITAL_LOOKALIKES: [(char, char); 4] = [('В', 'B'), ('М', 'M'), ('Н', 'H'), ('Т', 'T')];
+
+fn lowercase(ch: char) -> char {
+    ch.to_lowercase().next().unwrap_or(ch)
+}
+
+/// Same letter in any case, or Cyrillic and Latin letters that look the same
+fn same_letter(a: char, b: char) -> bool {
+    let pair =
+        |pairs: &[(char, char)], a, b| pairs.iter().any(|&pair| pair == (a, b) || pair == (b, a));
+    let (lower_a, lower_b) = (lowercase(a), lowercase(b));
+    lower_a == lower_b || pair(&LOOKALIKES, lower_a, lower_b) || pair(&CAPITAL_LOOKALIKES, a, b)
+}
+
+/// Case insensitive search that finds "Вася" and "Bacя" by each other
+fn contains_lookalike(text: &str, part: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let part: Vec<char> = part.chars().collect();
+    part.is_empty()
+        || text
+            .windows(part.len())
+            .any(|window| window.iter().zip(&part).all(|(&a, &b)| same_letter(a, b)))
+}
+
+/// IPv4 address with optional prefix length, single address is `/32`
+#[derive(Debug, Clone, Copy)]
+struct Ipv4Net {
+    addr: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Ipv4Net {
+    fn mask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - u32::from(self.prefix))
+            .unwrap_or(0)
+    }
+
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & self.mask() == u32::from(self.addr) & self.mask()
+    }
+}
+
+impl std::str::FromStr for Ipv4Net {
+    type Err = &'static str;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, prefix.parse().map_err(|_| "Wrong prefix length")?),
+            None => (text, 32),
+        };
+        if prefix > 32 {
+            return Err("Prefix length should be at most 32");
+        }
+        let addr = addr.parse().map_err(|_| "Wrong IPv4 address")?;
+        Ok(Ipv4Net { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for Ipv4Net {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.prefix == 32 {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+impl Serialize for Ipv4Net {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value: Option<String> = Deserialize::deserialize(deserializer)?;
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ClientsSort {
+    Name,
+    Seen,
+    Id,
+    Lvl,
+    Hp,
+    MapId,
+    MapPid,
+    AccessLevel,
+    Gamemode,
+}
+
+impl Default for ClientsSort {
+    fn default() -> Self {
+        ClientsSort::Name
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder::Asc
+    }
+}
+
+/// Only the form, the list itself is loaded from `/api/v1/clients` with the same query
+pub async fn clients(
+    data: web::Data<AppState>,
+    query: Option<web::Query<ClientsQuery>>,
+    impersonating: meta::Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    // wrong query is reported by the API
+    let query = query.map(web::Query::into_inner).unwrap_or_default();
     let res = web::block(move || {
+        templates::render(
+            "gm_clients.html",
+            &ClientsPage { query: &query },
+            impersonating.render_config(&data.config.host),
+        )
+    })
+    .await?
+    .map_err(super::internal_error)?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(res))
+}
+
+pub async fn api_clients(
+    data: web::Data<AppState>,
+    query: web::Query<ClientsQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let res = clients_json(data, query.into_inner())
+        .await
+        .map_err(|err| {
+            eprintln!("api_clients error: {:?}", err);
+            super::api_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal error".into())
+        })?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(res))
+}
+
+/// Page of clients passed the filters of `query`
+async fn clients_json(
+    data: web::Data<AppState>,
+    query: ClientsQuery,
+) -> Result<String, ClientsError> {
+    let members = match &data.mrhandy {
+        Some(mrhandy) => mrhandy.clone_members().await,
+        None => None,
+    };
+    web::block(move || {
         let clients = data.critters_db.list_clients();
         let list = ClientsList::new(
             clients.clients().iter(),
             &data.sled_db.root,
             members.as_ref(),
+            &query,
         );
-        list.render(&data.config.host)
+        serde_json::to_string(&list).map_err(ClientsError::Json)
     })
-    .await?
-    .map_err(super::internal_error)?;
+    .await
+    .map_err(ClientsError::Blocking)?
+}
 
-    Ok(HttpResponse::Ok().content_type("text/html").body(res))
+#[derive(Debug)]
+enum ClientsError {
+    Blocking(BlockingError),
+    Json(serde_json::Error),
+}
+
+#[derive(Debug, Serialize)]
+struct ClientsPage<'a> {
+    query: &'a ClientsQuery,
 }
 
 #[derive(Debug, Serialize)]
 struct ClientsList<'a> {
+    /// Clients passed the filters, on all pages
+    total: usize,
+    page: usize,
+    pages: usize,
+    per_page: usize,
     clients: Vec<ClientRow<'a>>,
 }
 #[derive(Debug, Serialize)]
@@ -40,6 +352,8 @@ struct ClientRow<'a> {
     file: Cow<'a, str>,
     info: Option<ClientRowInfo<'a>>,
     last_seen: Option<(String, bool)>,
+    /// Seconds since the client file was changed
+    seen_secs: Option<u64>,
 }
 #[derive(Debug, Serialize)]
 struct ClientRowInfo<'a> {
@@ -52,26 +366,63 @@ struct ClientRowInfo<'a> {
     st_access_level: i32,
     qst_vision: i32,
     gamemode: &'static str,
-    discord: Result<OwnerInfo<'a>, &'static str>,
+    discord: Result<Owner<'a>, &'static str>,
     ip: &'a [Ipv4Addr],
 }
 
+impl<'a> ClientRow<'a> {
+    fn cmp_by(&self, other: &Self, sort: ClientsSort) -> Ordering {
+        fn key<T: Ord>(row: &ClientRow, f: impl Fn(&ClientRowInfo) -> T) -> Option<T> {
+            row.info.as_ref().map(f)
+        }
+        let by_sort = match sort {
+            ClientsSort::Name => Ordering::Equal,
+            // not seen at all is the oldest
+            ClientsSort::Seen => {
+                let seen = |row: &ClientRow| row.seen_secs.unwrap_or(u64::MAX);
+                seen(self).cmp(&seen(other))
+            }
+            ClientsSort::Id => key(self, |info| info.id).cmp(&key(other, |info| info.id)),
+            ClientsSort::Lvl => key(self, |info| info.lvl).cmp(&key(other, |info| info.lvl)),
+            ClientsSort::Hp => key(self, |info| info.hp).cmp(&key(other, |info| info.hp)),
+            ClientsSort::MapId => {
+                key(self, |info| info.map_id).cmp(&key(other, |info| info.map_id))
+            }
+            ClientsSort::MapPid => {
+                key(self, |info| info.map_pid).cmp(&key(other, |info| info.map_pid))
+            }
+            ClientsSort::AccessLevel => {
+                key(self, |info| info.st_access_level).cmp(&key(other, |info| info.st_access_level))
+            }
+            ClientsSort::Gamemode => {
+                key(self, |info| info.gamemode).cmp(&key(other, |info| info.gamemode))
+            }
+        };
+        by_sort.then_with(|| self.name.cmp(other.name))
+    }
+}
 const GAMEMODS: [&str; fos::GAME_MAX as usize] =
     ["START", "ADVENTURE", "SURVIVAL", "ARCADE", "TEST"];
 
-fn get_name<'a>(
+fn get_owner<'a>(
     members: Option<&'a mrhandy::Members>,
     root: &Root,
     id: u32,
-) -> Result<OwnerInfo<'a>, &'static str> {
-    let members = members.ok_or("Main guild unavaible")?;
-    let owner = get_ownership(root, id)
+) -> Result<Owner<'a>, &'static str> {
+    let id = get_ownership(root, id)
         .map_err(|_| "Err")?
         .ok_or("No owner")?;
-    let member = match members.get(owner) {
-        Some(member) => member,
-        None => return Ok(OwnerInfo::Id(owner)),
-    };
+    Ok(Owner {
+        id,
+        member: get_member(members, id),
+    })
+}
+
+fn get_member(members: Option<&mrhandy::Members>, id: u64) -> Result<OwnerInfo<'_>, &'static str> {
+    let member = members
+        .ok_or("Main guild unavaible")?
+        .get(id)
+        .ok_or("Not in main guild")?;
     let name = member.user_name.as_str();
     Ok(match member.nick.as_ref() {
         None => OwnerInfo::Name(name),
@@ -79,58 +430,108 @@ fn get_name<'a>(
     })
 }
 
+/// Owner id from the ownership record, with the member names if the guild knows them
+#[derive(Debug, Serialize)]
+struct Owner<'a> {
+    #[serde(serialize_with = "id_as_string")]
+    id: u64,
+    member: Result<OwnerInfo<'a>, &'static str>,
+}
+
+// Discord ids don't fit into JS numbers
+fn id_as_string<S: serde::Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(id)
+}
+
+impl<'a> Owner<'a> {
+    /// Exact id or part of the name or nickname, case insensitive
+    fn matches(&self, filter: &str) -> bool {
+        self.id.to_string() == filter
+            || self
+                .member
+                .as_ref()
+                .map_or(false, |member| member.matches(filter))
+    }
+}
+
 #[derive(Debug, Serialize)]
 enum OwnerInfo<'a> {
-    Id(u64),
     Name(&'a str),
     NickName(&'a str, &'a str),
 }
 
+impl<'a> OwnerInfo<'a> {
+    fn matches(&self, filter: &str) -> bool {
+        let filter = filter.to_lowercase();
+        match self {
+            OwnerInfo::Name(name) => name.to_lowercase().contains(&filter),
+            OwnerInfo::NickName(name, nick) => {
+                name.to_lowercase().contains(&filter) || nick.to_lowercase().contains(&filter)
+            }
+        }
+    }
+}
+
 impl<'a> ClientsList<'a> {
     fn new<I: Iterator<Item = (&'a String, &'a ClientRecord)>>(
         clients: I,
         root: &Root,
         members: Option<&'a mrhandy::Members>,
+        query: &ClientsQuery,
     ) -> Self {
+        let mut clients: Vec<_> = clients
+            .map(|(name, record)| {
+                let info = record.info.as_ref().map(|info| ClientRowInfo {
+                    id: info.id,
+                    lvl: info.param(Param::ST_LEVEL),
+                    hp: info.param(Param::ST_CURRENT_HP),
+                    map_id: info.map_id,
+                    map_pid: info.map_pid,
+                    cond: info.cond(),
+                    st_access_level: info.param(Param::ST_ACCESS_LEVEL),
+                    qst_vision: info.param(Param::QST_VISION),
+                    gamemode: GAMEMODS
+                        [info.uparam(Param::QST_GAMEMODE).min(fos::GAME_MAX - 1) as usize],
+                    discord: get_owner(members, root, info.id),
+                    ip: &info.ip[..],
+                });
+                let elapsed = record.modified.and_then(|time| time.elapsed().ok());
+                ClientRow {
+                    info,
+                    name,
+                    file: os_str_debug(&record.filename),
+                    last_seen: elapsed.as_ref().map(ago),
+                    seen_secs: elapsed.map(|elapsed| elapsed.as_secs()),
+                }
+            })
+            .filter(|row| query.matches(row))
+            .collect();
+
+        clients.sort_by(|a, b| {
+            let ordering = a.cmp_by(b, query.sort);
+            match query.order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        });
+
+        let total = clients.len();
+        let per_page = query.per_page.clamp(1, MAX_PER_PAGE);
+        let pages = total.div_ceil(per_page).max(1);
+        let page = query.page.clamp(1, pages);
+        let clients = clients
+            .into_iter()
+            .skip((page - 1) * per_page)
+            .take(per_page)
+            .collect();
         Self {
-            clients: clients
-                .map(|(name, record)| {
-                    let info = record.info.as_ref().map(|info| ClientRowInfo {
-                        id: info.id,
-                        lvl: info.param(Param::ST_LEVEL),
-                        hp: info.param(Param::ST_CURRENT_HP),
-                        map_id: info.map_id,
-                        map_pid: info.map_pid,
-                        cond: info.cond(),
-                        st_access_level: info.param(Param::ST_ACCESS_LEVEL),
-                        qst_vision: info.param(Param::QST_VISION),
-                        gamemode: GAMEMODS
-                            [info.uparam(Param::QST_GAMEMODE).min(fos::GAME_MAX - 1) as usize],
-                        discord: get_name(members, root, info.id), //.unwrap_or_else(|err| Cow::Borrowed(err)),
-                        ip: &info.ip[..],
-                    });
-                    ClientRow {
-                        info,
-                        name,
-                        file: os_str_debug(&record.filename),
-                        last_seen: record
-                            .modified
-                            .and_then(|time| time.elapsed().ok())
-                            .as_ref()
-                            .map(ago),
-                    }
-                })
-                .collect(),
+            total,
+            page,
+            pages,
+            per_page,
+            clients,
         }
     }
-
-    fn render(&self, host: &Host) -> Result<String, templates::TemplatesError> {
-        templates::render(
-            "gm_clients.html",
-            self,
-            templates::RenderConfig { host: Some(host) },
-        )
-    }
 }
 
 fn ago(duration: &Duration) -> (String, bool) {
@@ -148,3 +549,254 @@ fn ago(duration: &Duration) -> (String, bool) {
         secs < 60 * 5,
     )
 }
+
+// ===== Avatar gallery =====
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct GalleryFilter {
+    /// Discord id, name or nickname of the owner
+    #[serde(default)]
+    owner: String,
+    /// Only avatars changed during the last days
+    days: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Gallery<'a> {
+    filter: &'a GalleryFilter,
+    avatars: Vec<GalleryRow<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct GalleryRow<'a> {
+    char_id: u32,
+    ver: u32,
+    secret: Option<u32>,
+    written: Option<u64>,
+    owner: Result<Owner<'a>, &'static str>,
+}
+
+impl<'a> GalleryRow<'a> {
+    fn owned_by(&self, filter: &str) -> bool {
+        self.owner
+            .as_ref()
+            .map_or(false, |owner| owner.matches(filter))
+    }
+}
+
+pub async fn avatars(
+    data: web::Data<AppState>,
+    filter: web::Query<GalleryFilter>,
+    impersonating: meta::Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let members = match &data.mrhandy {
+        Some(mrhandy) => mrhandy.clone_members().await,
+        None => None,
+    };
+    let res = web::block(move || {
+        let root = &data.sled_db.root;
+        let since = filter
+            .days
+            .map(|days| unix_now().saturating_sub(days * 24 * 60 * 60));
+        let owner = filter.owner.trim();
+
+        let mut avatars = vec![];
+        for char_id in image_characters(root)? {
+            let trunk = root.trunk(char_id, None, CharTrunk::default());
+            let (ver, secret) = match trunk.published_version(None) {
+                Ok(published) => published,
+                // nothing approved yet
+                Err(VersionedError::NotFound) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let written = trunk.get_image_time(ver)?;
+            if let Some(since) = since {
+                if written.map_or(true, |written| written < since) {
+                    continue;
+                }
+            }
+            let row = GalleryRow {
+                char_id,
+                ver,
+                secret,
+                written,
+                owner: get_owner(members.as_ref(), root, char_id),
+            };
+            if owner.is_empty() || row.owned_by(owner) {
+                avatars.push(row);
+            }
+        }
+        // recently changed first
+        avatars.sort_by(|a, b| b.written.cmp(&a.written));
+
+        templates::render(
+            "gm_avatars.html",
+            &Gallery {
+                filter: &*filter,
+                avatars,
+            },
+            impersonating.render_config(&data.config.host),
+        )
+        .map_err(GalleryError::Template)
+    })
+    .await?
+    .map_err(super::internal_error)?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(res))
+}
+
+#[derive(Debug)]
+enum GalleryError {
+    Versioned(VersionedError),
+    Template(templates::TemplatesError),
+}
+
+impl From<VersionedError> for GalleryError {
+    fn from(err: VersionedError) -> Self {
+        GalleryError::Versioned(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookalikes() {
+        assert!(contains_lookalike("Вася", "вася"));
+        assert!(contains_lookalike("Вася", "Bacя"));
+        assert!(contains_lookalike("Bacя", "ВАСЯ"));
+        assert!(contains_lookalike("Рокер", "POKEP"));
+        assert!(contains_lookalike("Рокер", "pokep"));
+        assert!(contains_lookalike("Мистер Икс", "икс"));
+        assert!(contains_lookalike("anything", ""));
+        assert!(!contains_lookalike("Вася", "Петя"));
+        assert!(!contains_lookalike("Ва", "Вася"));
+    }
+
+    #[test]
+    fn test_capital_lookalikes() {
+        assert!(contains_lookalike("НАТАША", "HATAШA"));
+        assert!(contains_lookalike("Hина", "Нина"));
+        // small letters of these don't look alike
+        assert!(!contains_lookalike("нина", "hина"));
+        assert!(!contains_lookalike("Hина", "нина"));
+        assert!(!contains_lookalike("вот", "bot"));
+        assert!(!contains_lookalike("том", "tom"));
+    }
+
+    fn client_row(owner: Result<Owner<'static>, &'static str>) -> ClientRow<'static> {
+        ClientRow {
+            name: "Вася",
+            file: Cow::Borrowed("Вася.client"),
+            info: Some(ClientRowInfo {
+                id: 5000,
+                lvl: 1,
+                hp: 100,
+                map_id: 0,
+                map_pid: 0,
+                cond: "ALIVE",
+                st_access_level: 0,
+                qst_vision: 0,
+                gamemode: "ADVENTURE",
+                discord: owner,
+                ip: &[],
+            }),
+            last_seen: None,
+            seen_secs: None,
+        }
+    }
+
+    fn owner_query(owner: &str) -> ClientsQuery {
+        ClientsQuery {
+            owner: owner.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_owner_filter() {
+        let id = 123456789012345678;
+        let cached = client_row(Ok(Owner {
+            id,
+            member: Ok(OwnerInfo::NickName("vasya", "Vasiliy")),
+        }));
+        let unknown = client_row(Ok(Owner {
+            id,
+            member: Err("Main guild unavaible"),
+        }));
+        let orphan = client_row(Err("No owner"));
+
+        // id matches whether the guild knows the member or not
+        assert!(owner_query("123456789012345678").matches(&cached));
+        assert!(owner_query(" 123456789012345678 ").matches(&unknown));
+        assert!(!owner_query("12345678901234567").matches(&cached));
+        assert!(!owner_query("123456789012345678").matches(&orphan));
+
+        assert!(owner_query("VASYA").matches(&cached));
+        assert!(owner_query("sil").matches(&cached));
+        assert!(!owner_query("vasya").matches(&unknown));
+        assert!(owner_query("").matches(&orphan));
+
+        let avatar = GalleryRow {
+            char_id: 5000,
+            ver: 1,
+            secret: None,
+            written: None,
+            owner: Ok(Owner {
+                id,
+                member: Err("Not in main guild"),
+            }),
+        };
+        assert!(avatar.owned_by("123456789012345678"));
+        assert!(!avatar.owned_by("vasya"));
+    }
+
+    fn net(text: &str) -> Ipv4Net {
+        text.parse().unwrap()
+    }
+
+    fn ip(text: &str) -> Ipv4Addr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ipv4_net_parse() {
+        assert_eq!(net("1.2.3.4").prefix, 32);
+        assert_eq!(net("1.2.3.0/24").prefix, 24);
+        assert_eq!(net("0.0.0.0/0").prefix, 0);
+        assert!("1.2.3.4/33".parse::<Ipv4Net>().is_err());
+        assert!("1.2.3.4/".parse::<Ipv4Net>().is_err());
+        assert!("1.2.3.4/-1".parse::<Ipv4Net>().is_err());
+        assert!("/24".parse::<Ipv4Net>().is_err());
+        assert!("1.2.3".parse::<Ipv4Net>().is_err());
+        assert!("".parse::<Ipv4Net>().is_err());
+    }
+
+    #[test]
+    fn test_ipv4_net_contains() {
+        let single = net("1.2.3.4");
+        assert!(single.contains(ip("1.2.3.4")));
+        assert!(!single.contains(ip("1.2.3.5")));
+
+        let subnet = net("1.2.3.0/24");
+        assert!(subnet.contains(ip("1.2.3.0")));
+        assert!(subnet.contains(ip("1.2.3.255")));
+        assert!(!subnet.contains(ip("1.2.4.0")));
+        // host bits of the network address are ignored
+        assert!(net("1.2.3.99/24").contains(ip("1.2.3.1")));
+
+        let any = net("0.0.0.0/0");
+        assert!(any.contains(ip("0.0.0.0")));
+        assert!(any.contains(ip("255.255.255.255")));
+        assert!(net("1.2.3.4/0").contains(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn test_ipv4_net_display() {
+        assert_eq!(net("1.2.3.4").to_string(), "1.2.3.4");
+        assert_eq!(net("1.2.3.4/32").to_string(), "1.2.3.4");
+        assert_eq!(net("1.2.3.0/24").to_string(), "1.2.3.0/24");
+        assert_eq!(net("0.0.0.0/0").to_string(), "0.0.0.0/0");
+    }
+}
@@ -0,0 +1,193 @@
+use std::ffi::CString;
+
+use actix_multipart::Multipart;
+use actix_web::{http::header, web, HttpResponse};
+use image::{imageops::FilterType, DynamicImage};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    avatar::{self, AvatarUploadError, SaveOptions, Target},
+    meta, AppState,
+};
+use crate::{
+    bridge,
+    config::ImageSlot,
+    database::{CharTrunk, Leaf, VersionedError},
+    templates,
+    utils::blocking,
+};
+
+// ===== Image slot editor =====
+
+#[derive(Debug, Serialize)]
+struct ImageEditor<'a> {
+    char_id: u32,
+    slot: &'a str,
+    width: u32,
+    height: u32,
+    max_bytes: usize,
+    formats: &'a [String],
+    /// Current version and its secret
+    current: Option<(u32, Option<u32>)>,
+}
+
+pub async fn edit(
+    path: web::Path<(u32, String)>,
+    data: web::Data<AppState>,
+    impersonating: meta::Impersonating,
+) -> Result<HttpResponse, AvatarUploadError> {
+    let (char_id, slot_name) = path.into_inner();
+    let body = blocking(move || {
+        let slot = data
+            .config
+            .image_slot(&slot_name)
+            .ok_or(AvatarUploadError::UnknownSlot)?;
+        let current = match data
+            .sled_db
+            .root
+            .trunk(char_id, None, CharTrunk::slot(&slot.name))
+            .published_version(None)
+        {
+            Ok(current) => Some(current),
+            Err(VersionedError::NotFound) => None,
+            Err(err) => return Err(AvatarUploadError::SledVersioned(err)),
+        };
+        let editor = ImageEditor {
+            char_id,
+            slot: &slot.name,
+            width: slot.width,
+            height: slot.height,
+            max_bytes: slot.max_bytes,
+            formats: &slot.formats,
+            current,
+        };
+        templates::render(
+            "edit_image.html",
+            &editor,
+            impersonating.render_config(&data.config.host),
+        )
+        .map_err(AvatarUploadError::Template)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+// ===== Upload image =====
+
+#[derive(Serialize)]
+struct Uploaded {
+    ver: u32,
+    pending: bool,
+}
+
+/// Takes image from `image` field of multipart form and fits it into the slot dimensions,
+/// moderation and palette settings are the same as for avatars
+pub async fn upload(
+    path: web::Path<(u32, String)>,
+    data: web::Data<AppState>,
+    payload: Multipart,
+) -> Result<HttpResponse, AvatarUploadError> {
+    let (char_id, slot_name) = path.into_inner();
+    let slot = data
+        .config
+        .image_slot(&slot_name)
+        .ok_or(AvatarUploadError::UnknownSlot)?
+        .clone();
+    let file = avatar::read_file(payload, "image", slot.max_bytes).await?;
+
+    let format =
+        image::guess_format(&file).map_err(|_| AvatarUploadError::ImageFormat("unknown".into()))?;
+    let allowed = slot
+        .formats
+        .iter()
+        .filter_map(|format| avatar::image_format(format))
+        .any(|allowed| allowed == format);
+    if !allowed {
+        return Err(AvatarUploadError::ImageFormat(format!("{:?}", format)));
+    }
+
+    let root = data.sled_db.root.clone();
+    let sender = data.bridge.get_sender();
+    let options = SaveOptions::new(&data);
+    let moderation = options.moderation;
+    let (name, leaf) = blocking(move || {
+        let target = Target::Slot(&slot);
+        let leaf = avatar::save_image(&root, char_id, &file, Some(format), target, &options)?;
+        Ok((slot.name, leaf))
+    })
+    .await?;
+    let ver = leaf.ver;
+    if !moderation {
+        update_char_image(sender, char_id, &name, leaf)?;
+    }
+
+    Ok(HttpResponse::Ok().json(Uploaded {
+        ver,
+        pending: moderation,
+    }))
+}
+
+/// Crops the image to aspect ratio of the slot around its center and scales it to the slot size
+pub(super) fn fit_to_slot(image: DynamicImage, slot: &ImageSlot) -> DynamicImage {
+    if image.width() == slot.width && image.height() == slot.height {
+        image
+    } else {
+        image.resize_to_fill(slot.width, slot.height, FilterType::Lanczos3)
+    }
+}
+
+pub(super) fn update_char_image(
+    sender: Option<bridge::MsgOutSender>,
+    id: u32,
+    slot: &str,
+    leaf: Leaf<()>,
+) -> Result<(), AvatarUploadError> {
+    match (sender, leaf) {
+        (
+            Some(mut sender),
+            Leaf {
+                ver,
+                secret: Some(secret),
+                ..
+            },
+        ) => {
+            // slot names are validated in config, so they never contain zero bytes
+            let slot = CString::new(slot).expect("Slot name without zero bytes");
+            sender
+                .try_send(bridge::MsgOut::UpdateCharImage {
+                    id,
+                    slot,
+                    ver,
+                    secret,
+                })
+                .map_err(|_| AvatarUploadError::FuturesSyncSend)
+        }
+        _ => Ok(()),
+    }
+}
+
+// ===== Show image =====
+
+#[derive(Deserialize)]
+pub struct SlotQuery {
+    ver: Option<u32>,
+    secret: Option<u32>,
+}
+
+pub async fn show(
+    path: web::Path<(u32, String)>,
+    query: web::Query<SlotQuery>,
+    if_none_match: Option<web::Header<header::IfNoneMatch>>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let (char_id, slot_name) = path.into_inner();
+    let bark = match data.config.image_slot(&slot_name) {
+        Some(slot) => CharTrunk::slot(&slot.name),
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let SlotQuery { ver, secret } = *query;
+    avatar::serve_image(data, char_id, bark, ver, secret, None, if_none_match).await
+}
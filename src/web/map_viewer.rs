@@ -1,7 +1,7 @@
 use actix_web::error::BlockingError;
 use serde::Serialize;
 
-use super::{web, AppState, HttpResponse};
+use super::{meta, web, AppState, HttpResponse};
 use crate::{templates, utils::blocking};
 
 #[derive(Debug, Serialize)]
@@ -69,6 +69,7 @@ impl From<BlockingError> for MapViewError {
 pub async fn view(
     path: web::Path<std::path::PathBuf>,
     data: web::Data<AppState>,
+    impersonating: meta::Impersonating,
 ) -> actix_web::Result<HttpResponse> {
     use draw_geometry::fo as geometry;
     use primitives::Hex;
@@ -161,9 +162,7 @@ pub async fn view(
                         tiles,
                         objects,
                     },
-                    templates::RenderConfig {
-                        host: Some(&data.config.host),
-                    },
+                    impersonating.render_config(&data.config.host),
                 )
                 .map_err(MapViewError::Template)
             },
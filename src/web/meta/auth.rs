@@ -15,6 +15,9 @@ pub async fn auth(
         "Auth: session: {:?}",
         session.get::<String>(DISCORD_CSRF_COOKIE_NAME)
     );*/
+    if !data.config.discord_login() {
+        return Err(access_denied("Discord login is disabled")().into());
+    }
     let res = match session.get::<CsrfToken>(DISCORD_CSRF_COOKIE_NAME) {
         Ok(Some(csrf)) if csrf.secret() == &params.state => data
             .oauth
@@ -30,7 +33,14 @@ pub async fn auth(
     session.remove(DISCORD_CSRF_COOKIE_NAME);
     let token = res?;
 
-    let path = get_user_self();
+    let path = data
+        .config
+        .discord
+        .as_ref()
+        .expect("Discord config")
+        .oauth2
+        .user_info_url
+        .clone();
 
     let auth = format!("Bearer {}", token.access_token().secret());
     let identity = oauth_reqwest::get(&data.reqwest, auth.clone(), path)
@@ -39,7 +49,7 @@ pub async fn auth(
     let user: DiscordUser = serde_json::from_str(&identity).map_err(internal_error)?;
     let user_id: u64 = user.id.parse().map_err(internal_error)?;
 
-    session.insert(DISCORD_USER_ID_COOKIE_NAME, user_id)?;
+    local::start_session(&session, user_id)?;
     let location: String = if let Some(location) = session.get(LOCATION_AFTER_AUTH)? {
         session.remove(LOCATION_AFTER_AUTH);
         location
@@ -54,18 +64,6 @@ pub async fn auth(
         .finish())
 }
 
-fn get_user_self() -> String {
-    format!("{}/users/@me", DISCORD_API_URL)
-}
-/*
-fn get_user_guilds() -> String {
-    format!("{}/users/@me/guilds", DISCORD_API_URL)
-}
-
-fn get_guild_member(guild: &str, user: &str) -> String {
-    format!("{}/guilds/{}/members/{}", DISCORD_API_URL, guild, user)
-}
-*/
 #[derive(Deserialize, Debug)]
 #[allow(dead_code)]
 struct DiscordUser {
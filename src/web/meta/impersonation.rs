@@ -0,0 +1,249 @@
+use std::fmt;
+
+use actix_service::Service;
+use actix_session::SessionExt;
+use actix_web::{
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    FromRequest,
+};
+use futures::future::{ready, Ready};
+use serde::Serialize;
+
+use super::*;
+use crate::{
+    config::Host,
+    database::{
+        audit::{self, AuditRecord},
+        unix_now, VersionedError,
+    },
+    templates,
+    utils::blocking,
+    web::csrf,
+};
+
+const IMPERSONATION_COOKIE_NAME: &str = "impersonation";
+pub const EXIT_URL: &str = "/meta/impersonate/exit";
+const IMPERSONATE_URL: &str = "/admin/impersonate";
+/// Audit records shown on the impersonation page
+const AUDIT_SHOWN: usize = 50;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImpersonationTarget {
+    User(u64),
+    Rank(Rank),
+}
+
+impl fmt::Display for ImpersonationTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImpersonationTarget::User(user_id) => write!(f, "user {}", user_id),
+            ImpersonationTarget::Rank(rank) => {
+                write!(f, "rank {}", rank.config_key().unwrap_or("unknown"))
+            }
+        }
+    }
+}
+
+/// Stored in the admin's session, the admin stays logged in as themselves
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Impersonation {
+    pub target: ImpersonationTarget,
+    pub started: u64,
+}
+
+pub fn get_impersonation(session: &Session) -> Option<Impersonation> {
+    match session.get(IMPERSONATION_COOKIE_NAME) {
+        Ok(impersonation) => impersonation,
+        Err(err) => {
+            eprintln!("get_impersonation error: {:?}", err);
+            session.remove(IMPERSONATION_COOKIE_NAME);
+            None
+        }
+    }
+}
+
+/// Who is being impersonated, for the banner on rendered pages
+pub struct Impersonating {
+    label: Option<String>,
+    /// CSRF field for the forms of the page
+    csrf_input: Option<String>,
+}
+
+impl Impersonating {
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Every page is rendered with it, so none of them misses the banner
+    pub fn render_config<'a>(&'a self, host: &'a Host) -> templates::RenderConfig<'a> {
+        templates::RenderConfig {
+            host: Some(host),
+            impersonating: self.label(),
+            csrf_input: self.csrf_input.as_deref(),
+            ..Default::default()
+        }
+    }
+}
+
+impl FromRequest for Impersonating {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let session = req.get_session();
+        let impersonation = get_impersonation(&session);
+        ready(Ok(Impersonating {
+            label: impersonation.map(|impersonation| impersonation.target.to_string()),
+            csrf_input: csrf::session_csrf_input(&session),
+        }))
+    }
+}
+
+/// Impersonation is read only: only GET and HEAD pass, except the exit and logout
+pub fn impersonation_guard<
+    B: MessageBody,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>> {
+    let method = req.method();
+    let read_only = method == &Method::GET
+        || method == &Method::HEAD
+        || req.path() == EXIT_URL
+        || req.path() == LOGOUT_URL;
+    let allowed = read_only || get_impersonation(&req.get_session()).is_none();
+    let fut = srv.call(req);
+    async move {
+        if allowed {
+            fut.await
+        } else {
+            Err(access_denied("Read only while impersonating")())?
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImpersonateForm {
+    #[serde(default)]
+    user_id: String,
+    #[serde(default)]
+    rank: String,
+}
+
+impl ImpersonateForm {
+    fn target(&self) -> Result<ImpersonationTarget, &'static str> {
+        match (self.user_id.trim(), self.rank.trim()) {
+            ("", "") => Err("Choose user or rank"),
+            (user_id, "") => user_id
+                .parse()
+                .map(ImpersonationTarget::User)
+                .map_err(|_| "User id should be a number"),
+            ("", rank) => Rank::from_config_key(rank)
+                .map(ImpersonationTarget::Rank)
+                .ok_or("Unknown rank"),
+            _ => Err("Choose either user or rank"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ImpersonatePage {
+    ranks: Vec<&'static str>,
+    records: Vec<AuditRecord>,
+}
+
+pub async fn impersonate_page(
+    data: web::Data<AppState>,
+    impersonating: Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let body = blocking(move || {
+        let page = ImpersonatePage {
+            ranks: [Rank::Player, Rank::GameMaster, Rank::Developer, Rank::Admin]
+                .iter()
+                .filter_map(|rank| rank.config_key())
+                .collect(),
+            records: audit::recent(&data.sled_db.root, AUDIT_SHOWN)
+                .map_err(ImpersonationError::Versioned)?,
+        };
+        templates::render(
+            "admin_impersonate.html",
+            &page,
+            impersonating.render_config(&data.config.host),
+        )
+        .map_err(ImpersonationError::Template)
+    })
+    .await
+    .map_err(internal_error)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+pub async fn start_impersonation(
+    data: web::Data<AppState>,
+    member: Member,
+    session: Session,
+    form: web::Form<ImpersonateForm>,
+) -> actix_web::Result<HttpResponse> {
+    if member.by_token {
+        return Err(access_denied("Impersonation needs the browser session")().into());
+    }
+    let admin_id = member.id;
+    let target = form.target().map_err(|err| bad_request(err)())?;
+    let details = target.to_string();
+    let root = data.sled_db.root.clone();
+    blocking(move || audit::record(&root, admin_id, "impersonation_start", details))
+        .await
+        .map_err(internal_error)?;
+    println!("{} started impersonation of {}", admin_id, target);
+    session.insert(
+        IMPERSONATION_COOKIE_NAME,
+        Impersonation {
+            target,
+            started: unix_now(),
+        },
+    )?;
+    Ok(HttpResponse::SeeOther()
+        .append_header((header::LOCATION, "/"))
+        .finish())
+}
+
+pub async fn exit_impersonation(
+    data: web::Data<AppState>,
+    session: Session,
+) -> actix_web::Result<HttpResponse> {
+    if let (Some(admin_id), Some(impersonation)) =
+        (get_user_id(&session), get_impersonation(&session))
+    {
+        session.remove(IMPERSONATION_COOKIE_NAME);
+        let details = format!(
+            "{} for {} s",
+            impersonation.target,
+            unix_now().saturating_sub(impersonation.started)
+        );
+        let root = data.sled_db.root.clone();
+        blocking(move || audit::record(&root, admin_id, "impersonation_exit", details))
+            .await
+            .map_err(internal_error)?;
+    }
+    Ok(HttpResponse::SeeOther()
+        .append_header((header::LOCATION, IMPERSONATE_URL))
+        .finish())
+}
+
+#[derive(Debug)]
+enum ImpersonationError {
+    Versioned(VersionedError),
+    Template(templates::TemplatesError),
+    Blocking,
+}
+
+impl From<actix_web::error::BlockingError> for ImpersonationError {
+    fn from(_err: actix_web::error::BlockingError) -> Self {
+        ImpersonationError::Blocking
+    }
+}
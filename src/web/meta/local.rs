@@ -0,0 +1,108 @@
+use serde::Serialize;
+
+use super::{ownership::claim_limits, *};
+use crate::{
+    database::{ownership, VersionedError},
+    templates,
+    utils::blocking,
+    web::{
+        avatar, csrf,
+        throttle::{self, Throttle},
+    },
+};
+
+pub const LOGIN_URL: &str = "/meta/login/authkey";
+
+#[derive(Serialize)]
+struct LoginPage {
+    discord: bool,
+}
+
+#[derive(Deserialize)]
+pub struct AuthkeyForm {
+    char_id: u32,
+    auth: String,
+}
+
+pub async fn authkey_form(
+    data: web::Data<AppState>,
+    session: Session,
+) -> actix_web::Result<HttpResponse> {
+    if !data.config.authkey.login {
+        return Err(access_denied("Authkey login is disabled")().into());
+    }
+    // anonymous visitor needs a session with CSRF token to post the form
+    let csrf_input = csrf::csrf_input(&session);
+    let page = LoginPage {
+        discord: data.config.discord_login(),
+    };
+    let body = templates::render(
+        "login_authkey.html",
+        &page,
+        templates::RenderConfig {
+            host: Some(&data.config.host),
+            csrf_input: Some(&csrf_input),
+            ..Default::default()
+        },
+    )
+    .map_err(internal_error)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+pub async fn authkey_login(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    session: Session,
+    form: web::Form<AuthkeyForm>,
+) -> actix_web::Result<HttpResponse> {
+    if !data.config.authkey.login {
+        return Err(access_denied("Authkey login is disabled")().into());
+    }
+    if let Err(response) = throttle::check(&data, &req, Throttle::Claim, None) {
+        return Ok(response);
+    }
+    let AuthkeyForm { char_id, auth } = form.into_inner();
+    let (auth, _auth_string) =
+        avatar::parse_auth_hex(auth.trim()).ok_or_else(bad_request("Authkey is 24 hex digits"))?;
+    let user_id = login_with_authkey(&data, char_id, auth)
+        .await
+        .map_err(|err| match err {
+            VersionedError::TooManyAttempts => {
+                access_denied("Too many attempts, try later")().into()
+            }
+            VersionedError::AccessDenied => access_denied("Wrong authkey")().into(),
+            err => internal_error(err),
+        })?;
+    start_session(&session, user_id)?;
+
+    let location: String = match session.get(LOCATION_AFTER_AUTH)? {
+        Some(location) => {
+            session.remove(LOCATION_AFTER_AUTH);
+            location
+        }
+        None => format!("/char/{}/edit/avatar", char_id),
+    };
+    Ok(HttpResponse::SeeOther()
+        .append_header((header::LOCATION, location))
+        .finish())
+}
+
+/// Checks authkey and returns id of the local account of the character
+pub(super) async fn login_with_authkey(
+    data: &AppState,
+    char_id: u32,
+    auth: avatar::AuthVec,
+) -> Result<u64, VersionedError> {
+    let root = data.sled_db.root.clone();
+    let limits = claim_limits(data);
+    blocking(move || ownership::authkey_login(&root, char_id, &auth, limits)).await
+}
+
+/// New session id on every login, so id known before it can't be used to hijack the session
+pub(super) fn start_session(session: &Session, user_id: u64) -> actix_web::Result<()> {
+    session.renew();
+    session.insert(DISCORD_USER_ID_COOKIE_NAME, user_id)?;
+    Ok(())
+}
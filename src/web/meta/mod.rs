@@ -12,21 +12,40 @@ use crate::web::{internal_error, restrict::Restrict, AppState};
 
 const DISCORD_CSRF_COOKIE_NAME: &str = "csrf_discord";
 const DISCORD_USER_ID_COOKIE_NAME: &str = "user_id_discord";
-const DISCORD_API_URL: &str = "https://discordapp.com/api";
 
 const LOCATION_AFTER_AUTH: &str = "location_after_auth";
+pub const LOGOUT_URL: &str = "/meta/logout";
 
 mod auth;
+mod impersonation;
+mod local;
 mod ownership;
+pub mod permission;
 mod rank;
-pub use ownership::restrict_ownership;
+mod sessions;
+mod settings;
+mod tokens;
+pub use ownership::{restrict_owner_or_history, restrict_owner_or_moderator, restrict_ownership};
 
 pub use self::{
     auth::auth,
-    rank::{extract_member, get_user_record, Rank},
+    impersonation::{
+        exit_impersonation, get_impersonation, impersonate_page, impersonation_guard,
+        start_impersonation, Impersonating, ImpersonationTarget,
+        EXIT_URL as IMPERSONATION_EXIT_URL,
+    },
+    local::{authkey_form, authkey_login},
+    permission::{restrict_permission, Permissions},
+    rank::{
+        extract_member, get_user_record, get_user_settings, rank_permissions, Member, Rank,
+        RankCache,
+    },
+    sessions::{admin_revoke_sessions, revoke_all_sessions, revoke_session, sessions},
+    settings::{admin_settings, admin_update_settings, settings, update_settings},
+    tokens::{create_token, revoke_token, tokens},
 };
 
-fn bad_request(text: &'static str) -> impl Fn() -> InternalError<&'static str> {
+pub fn bad_request(text: &'static str) -> impl Fn() -> InternalError<&'static str> {
     move || {
         let full_text = format!("Bad request: {:?}", text);
         InternalError::from_response(
@@ -38,6 +57,30 @@ fn bad_request(text: &'static str) -> impl Fn() -> InternalError<&'static str> {
     }
 }
 
+pub fn not_found(text: &'static str) -> impl Fn() -> InternalError<&'static str> {
+    move || {
+        let full_text = format!("Not found: {:?}", text);
+        InternalError::from_response(
+            text,
+            HttpResponse::NotFound()
+                .content_type("text/plain; charset=utf-8")
+                .body(full_text),
+        )
+    }
+}
+
+pub fn conflict(text: &'static str) -> impl Fn() -> InternalError<&'static str> {
+    move || {
+        let full_text = format!("Conflict: {:?}", text);
+        InternalError::from_response(
+            text,
+            HttpResponse::Conflict()
+                .content_type("text/plain; charset=utf-8")
+                .body(full_text),
+        )
+    }
+}
+
 pub fn access_denied(text: &'static str) -> impl Fn() -> InternalError<&'static str> {
     move || {
         let full_text = format!("Access denied: {:?}", text);
@@ -62,26 +105,37 @@ pub fn get_user_id(session: &Session) -> Option<u64> {
     }
 }
 
-pub async fn restrict_gm(req: HttpRequest) -> Result<Restrict, actix_web::Error> {
-    let first_rank = extract_member(&req).await?;
-    Ok(match first_rank {
-        Some(member) => match member.ranks.first() {
-            Some(rank) if rank >= &Rank::GameMaster => Restrict::Allow,
-            _ => Restrict::Deny("Rank is too low for this restricted zone".into()),
-        },
-        None => Restrict::Deny("Restricted zone".into()),
-    })
+/// Logged in user of session state, for server side session store
+pub fn session_user_id(state: &std::collections::HashMap<String, String>) -> Option<u64> {
+    state
+        .get(DISCORD_USER_ID_COOKIE_NAME)
+        .and_then(|user_id| serde_json::from_str(user_id).ok())
 }
 
+/// Sends to authkey login form when it's enabled, otherwise to Discord
 pub async fn login(
     //path: web::Path<String>,
     data: web::Data<AppState>,
     session: Session,
 ) -> actix_web::Result<HttpResponse> {
-    let (authorize_url, csrf_token) = data
-        .oauth
-        .as_ref()
-        .expect("OAuth config")
+    if data.config.authkey.login {
+        return Ok(HttpResponse::Found()
+            .append_header((header::LOCATION, local::LOGIN_URL))
+            .append_header((header::ACCESS_CONTROL_MAX_AGE, "0"))
+            .finish());
+    }
+    discord_login(data, session).await
+}
+
+pub async fn discord_login(
+    data: web::Data<AppState>,
+    session: Session,
+) -> actix_web::Result<HttpResponse> {
+    let oauth = match &data.oauth {
+        Some(oauth) if data.config.discord_login() => oauth,
+        _ => return Err(access_denied("Discord login is disabled")().into()),
+    };
+    let (authorize_url, csrf_token) = oauth
         .authorize_url(CsrfToken::new_random)
         .add_scope(Scope::new("identify".to_owned()))
         .url();
@@ -98,8 +152,9 @@ pub async fn login(
 }
 
 pub async fn logout(session: Session) -> actix_web::Result<HttpResponse> {
-    session.remove(DISCORD_USER_ID_COOKIE_NAME);
-    Ok(HttpResponse::Found()
+    // removes server side state too, so the old cookie can't be reused
+    session.purge();
+    Ok(HttpResponse::SeeOther()
         .append_header((header::LOCATION, "/"))
         .append_header((header::ACCESS_CONTROL_MAX_AGE, "0"))
         .finish())
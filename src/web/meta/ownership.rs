@@ -3,17 +3,35 @@ use actix_session::SessionExt;
 use super::*;
 use crate::{
     database::{
-        ownership::{get_auth, get_ownership, set_ownership},
-        VersionedError,
+        ownership::{claim_ownership, get_ownership, local_user_id, owned_characters, ClaimLimits},
+        UserSettings, VersionedError,
     },
     utils::blocking,
-    web::avatar,
+    web::{
+        avatar,
+        throttle::{self, Throttle},
+    },
 };
 
 enum AuthAction {
-    CheckOwnership { user_id: u64, char_id: u32 },
+    CheckOwnership {
+        user_id: u64,
+        char_id: u32,
+        settings: UserSettings,
+        impersonated: bool,
+    },
     //CheckAuthKey(super::avatar::AuthVec),
-    Auth,
+    Auth {
+        char_id: u32,
+    },
+}
+
+pub(super) fn claim_limits(data: &AppState) -> ClaimLimits {
+    ClaimLimits {
+        ttl: data.config.authkey.ttl(),
+        max_failures: data.config.authkey.max_claim_failures,
+        window: data.config.authkey.throttle_window(),
+    }
 }
 
 fn extract_auth(req: &HttpRequest) -> Option<avatar::AuthVec> {
@@ -36,17 +54,20 @@ async fn restrict_client_inner(req: &HttpRequest) -> Result<AuthAction, actix_we
     let member = extract_member(req);
 
     if let Some(member) = member.await? {
-        return match member.ranks.first() {
-            Some(rank) if rank >= &Rank::Player => Ok(AuthAction::CheckOwnership {
+        return if member.permissions.allows(permission::OWN_CHARACTERS) {
+            Ok(AuthAction::CheckOwnership {
                 user_id: member.id,
                 char_id: url_id,
-            }),
-            _ => Err(access_denied("Rank is too low for this restricted zone")().into()),
+                settings: member.settings,
+                impersonated: member.impersonator.is_some(),
+            })
+        } else {
+            Err(access_denied("No permission for this restricted zone")().into())
         };
     }
 
     if method == &Method::GET {
-        Ok(AuthAction::Auth)
+        Ok(AuthAction::Auth { char_id: url_id })
     } else {
         Err(access_denied("Restricted zone")().into())
     }
@@ -58,9 +79,21 @@ pub async fn restrict_ownership(req: HttpRequest) -> Result<Restrict, actix_web:
     let data: &web::Data<AppState> = req.app_data().expect("Can't happend here");
 
     match action {
-        // Unlogged, redirect to login
-        AuthAction::Auth => {
+        // Unlogged, authkey from the game logs into local account, otherwise redirect to login
+        AuthAction::Auth { char_id } => {
             let session = req.get_session();
+            if let (true, Some(auth)) = (data.config.authkey.login, extract_auth(&req)) {
+                if let Err(response) = throttle::check(data, &req, Throttle::Claim, None) {
+                    return Ok(Restrict::response(req, response));
+                }
+                return Ok(match local::login_with_authkey(data, char_id, auth).await {
+                    Ok(user_id) => {
+                        local::start_session(&session, user_id)?;
+                        Restrict::Allow
+                    }
+                    Err(err) => Restrict::Deny(format!("Access denied: {:?}", err)),
+                });
+            }
             if let Some(path_and_query) = req.uri().path_and_query() {
                 session
                     .insert(LOCATION_AFTER_AUTH, path_and_query.as_str())
@@ -72,30 +105,43 @@ pub async fn restrict_ownership(req: HttpRequest) -> Result<Restrict, actix_web:
                 .await
         }
         // Logged and has role, checking for ownership
-        AuthAction::CheckOwnership { user_id, char_id } => {
+        AuthAction::CheckOwnership {
+            user_id,
+            char_id,
+            settings: member_settings,
+            impersonated,
+        } => {
             let root = data.sled_db.root.clone();
-            let auth_received = extract_auth(&req);
+            // claiming is a change, impersonation only looks
+            let auth_received = if impersonated {
+                None
+            } else {
+                extract_auth(&req)
+            };
+            if auth_received.is_some() {
+                let res = throttle::check(data, &req, Throttle::Claim, Some(user_id));
+                if let Err(response) = res {
+                    return Ok(Restrict::response(req, response));
+                }
+            }
+            let limits = claim_limits(data);
+            let max_characters = member_settings.max_characters;
             let result: Result<(), VersionedError> = blocking(move || {
                 let owner = get_ownership(&root, char_id)?;
-                dbg!(&owner);
-                match owner {
-                    Some(owner) if owner == user_id => {
-                        return Ok(());
-                    }
-                    None => {}
-                    _ => {
-                        return Err(VersionedError::AccessDenied);
-                    }
-                }
-
-                let auth_stored = get_auth(&root, char_id)?;
-                dbg!(&auth_stored);
-                match (owner, auth_stored, auth_received) {
-                    (None, Some(auth_stored), Some(auth_received))
-                        if &*auth_stored == &*auth_received =>
-                    {
-                        set_ownership(&root, char_id, user_id)?;
-                        Ok(())
+                // Discord user can take the character over from its local account
+                let claimable = match owner {
+                    Some(owner) if owner == user_id => return Ok(()),
+                    Some(owner) => owner == local_user_id(char_id),
+                    None => true,
+                };
+                match (claimable, auth_received) {
+                    (true, Some(auth_received)) => {
+                        if let Some(max) = max_characters {
+                            if owned_characters(&root, user_id)?.len() >= max as usize {
+                                return Err(VersionedError::LimitReached);
+                            }
+                        }
+                        claim_ownership(&root, char_id, user_id, &auth_received, limits)
                     }
                     _ => Err(VersionedError::AccessDenied),
                 }
@@ -109,3 +155,23 @@ pub async fn restrict_ownership(req: HttpRequest) -> Result<Restrict, actix_web:
         }
     }
 }
+
+/// Owners and members allowed to browse history of any character
+pub async fn restrict_owner_or_history(req: HttpRequest) -> Result<Restrict, actix_web::Error> {
+    if let Some(member) = extract_member(&req).await? {
+        if member.permissions.allows(permission::CHARACTER_HISTORY) {
+            return Ok(Restrict::Allow);
+        }
+    }
+    restrict_ownership(req).await
+}
+
+/// Owners and moderators, browsing history alone doesn't allow to change it
+pub async fn restrict_owner_or_moderator(req: HttpRequest) -> Result<Restrict, actix_web::Error> {
+    if let Some(member) = extract_member(&req).await? {
+        if member.permissions.allows(permission::MODERATE_AVATARS) {
+            return Ok(Restrict::Allow);
+        }
+    }
+    restrict_ownership(req).await
+}
@@ -0,0 +1,138 @@
+use std::{borrow::Cow, collections::BTreeSet};
+
+use futures::{future::LocalBoxFuture, FutureExt};
+
+use super::*;
+
+/// Edit avatar and start game as owned characters, claim new ones with authkey
+pub const OWN_CHARACTERS: &str = "own_characters";
+/// Clients list and character stats
+pub const VIEW_CLIENTS: &str = "view_clients";
+/// Gallery of all avatars
+pub const VIEW_AVATARS: &str = "view_avatars";
+/// Avatar moderation queue
+pub const MODERATE_AVATARS: &str = "moderate_avatars";
+/// Avatar history, restore and export of any character
+pub const CHARACTER_HISTORY: &str = "character_history";
+/// All private folders, `view_private:<name>` allows only one
+pub const VIEW_PRIVATE: &str = "view_private";
+pub const VIEW_MAPS: &str = "view_maps";
+pub const VIEW_DATA: &str = "view_data";
+pub const MANAGE_OWNERSHIP: &str = "manage_ownership";
+pub const MANAGE_USERS: &str = "manage_users";
+pub const CHECK_DATABASE: &str = "check_database";
+/// Look at the site as another user or rank, read only
+pub const IMPERSONATE: &str = "impersonate";
+
+const PLAYER: &[&str] = &[OWN_CHARACTERS];
+const GAMEMASTER: &[&str] = &[
+    OWN_CHARACTERS,
+    VIEW_CLIENTS,
+    VIEW_AVATARS,
+    MODERATE_AVATARS,
+    CHARACTER_HISTORY,
+    VIEW_PRIVATE,
+    VIEW_MAPS,
+    VIEW_DATA,
+];
+const ADMIN: &[&str] = &[
+    OWN_CHARACTERS,
+    VIEW_CLIENTS,
+    VIEW_AVATARS,
+    MODERATE_AVATARS,
+    CHARACTER_HISTORY,
+    VIEW_PRIVATE,
+    VIEW_MAPS,
+    VIEW_DATA,
+    MANAGE_OWNERSHIP,
+    MANAGE_USERS,
+    CHECK_DATABASE,
+    IMPERSONATE,
+];
+
+impl Rank {
+    /// Key of the rank in `discord.permissions` config
+    pub fn config_key(self) -> Option<&'static str> {
+        Some(match self {
+            Rank::Unknown => return None,
+            Rank::Player => "player",
+            Rank::GameMaster => "gamemaster",
+            Rank::Developer => "developer",
+            Rank::Admin => "admin",
+        })
+    }
+
+    pub fn from_config_key(key: &str) -> Option<Rank> {
+        [Rank::Player, Rank::GameMaster, Rank::Developer, Rank::Admin]
+            .iter()
+            .copied()
+            .find(|rank| rank.config_key() == Some(key))
+    }
+
+    pub fn default_permissions(self) -> &'static [&'static str] {
+        match self {
+            Rank::Unknown => &[],
+            Rank::Player => PLAYER,
+            Rank::GameMaster | Rank::Developer => GAMEMASTER,
+            Rank::Admin => ADMIN,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Permissions(BTreeSet<String>);
+
+impl Permissions {
+    pub fn grant<S: Into<String>>(&mut self, permissions: impl IntoIterator<Item = S>) {
+        self.0.extend(permissions.into_iter().map(Into::into));
+    }
+
+    /// Only `scopes` that are allowed, for tokens limited to a part of member's permissions
+    pub fn limit(&self, scopes: &[String]) -> Permissions {
+        Permissions(
+            scopes
+                .iter()
+                .filter(|scope| self.allows(scope))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+
+    /// `group:item` is also allowed by `group`
+    pub fn allows(&self, permission: &str) -> bool {
+        self.0.contains(permission)
+            || permission
+                .split_once(':')
+                .map_or(false, |(group, _item)| self.0.contains(group))
+    }
+}
+
+pub fn private_permission(name: &str) -> String {
+    format!("{}:{}", VIEW_PRIVATE, name)
+}
+
+/// Rule for `restrict` middleware that allows members with `permission`
+pub fn restrict_permission(
+    permission: impl Into<Cow<'static, str>>,
+) -> impl Clone + Fn(HttpRequest) -> LocalBoxFuture<'static, Result<Restrict, actix_web::Error>> {
+    let permission = permission.into();
+    move |req| {
+        let member = extract_member(&req);
+        let permission = permission.clone();
+        async move {
+            Ok(match member.await? {
+                Some(member) if member.permissions.allows(&permission) => Restrict::Allow,
+                Some(_) => Restrict::Deny(format!(
+                    "No permission for this restricted zone: {}",
+                    permission
+                )),
+                None => Restrict::Deny("Restricted zone".into()),
+            })
+        }
+        .boxed_local()
+    }
+}
@@ -1,51 +1,180 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use actix_session::SessionExt;
-use actix_web::web::Data;
-use mrhandy::FixedString;
+use actix_web::{dev::Payload, web::Data, FromRequest, HttpMessage};
+use futures::future::{FutureExt, LocalBoxFuture};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 
-use super::*;
+use super::{
+    impersonation::{get_impersonation, ImpersonationTarget},
+    permission::Permissions,
+    *,
+};
+use crate::{
+    database::{
+        ownership::local_char_id,
+        token::{self, ApiToken},
+        unix_now, CachedRoles, UserSettings, UserTrunk, VersionedError,
+    },
+    utils::blocking,
+};
 
-pub async fn get_ranks(data: Arc<AppState>, user_id: u64) -> Result<Vec<Rank>, &'static str> {
-    data.mrhandy
+/// Recently looked up Discord roles by user id
+#[derive(Default)]
+pub struct RankCache(Mutex<HashMap<u64, CachedRoles>>);
+
+impl RankCache {
+    fn get(&self, user_id: u64, ttl: u64) -> Option<CachedRoles> {
+        let now = unix_now();
+        self.0
+            .lock()
+            .get(&user_id)
+            .filter(|roles| now.saturating_sub(roles.fetched) < ttl)
+            .cloned()
+    }
+
+    /// Also forgets outdated entries of other users, so the cache doesn't grow forever
+    fn put(&self, user_id: u64, roles: CachedRoles, ttl: u64) {
+        let now = unix_now();
+        let mut cache = self.0.lock();
+        cache.retain(|_, roles| now.saturating_sub(roles.fetched) < ttl);
+        cache.insert(user_id, roles);
+    }
+}
+
+/// Roles from the gateway cache, while it's cold they are taken from the saved copy
+/// or from Discord REST API. Results are reused for `discord.rank_cache_ttl` seconds.
+/// When Discord can't be reached the outdated saved copy is used, but not for a member who left.
+async fn get_member_roles(data: &AppState, user_id: u64) -> Result<CachedRoles, &'static str> {
+    // tests have no bot, their users come from the fake OAuth provider
+    #[cfg(test)]
+    {
+        if let Some(roles) = data.fake_members.get(&user_id) {
+            return Ok(roles.clone());
+        }
+    }
+    let ttl = data
+        .config
+        .discord
         .as_ref()
         .expect("Discord config")
-        .with_guild_member(user_id, |guild, member| {
-            let mut ranks = mrhandy::MrHandy::get_roles(
-                guild,
-                member,
-                role_to_rank(&data.config.discord.as_ref().expect("Discord config").roles),
+        .rank_cache_ttl;
+    if let Some(roles) = data.rank_cache.get(user_id, ttl) {
+        return Ok(roles);
+    }
+    let mrhandy = data.mrhandy.as_ref().expect("Discord config");
+
+    let member = match mrhandy.cached_member_roles(user_id).await {
+        Ok(member) => member,
+        Err(err) => {
+            let root = data.sled_db.root.clone();
+            let saved = blocking(move || {
+                root.trunk(user_id, None, UserTrunk::default())
+                    .get_cached_roles()
+            })
+            .await
+            .unwrap_or_else(|load_err| {
+                eprintln!("Saved roles of {} are broken: {:?}", user_id, load_err);
+                None
+            });
+            if let Some(saved) = &saved {
+                if unix_now().saturating_sub(saved.fetched) < ttl {
+                    data.rank_cache.put(user_id, saved.clone(), ttl);
+                    return Ok(saved.clone());
+                }
+            }
+            eprintln!(
+                "Roles of {} aren't cached: {}, asking Discord",
+                user_id, err
             );
-            ranks.sort_by_key(|key| std::cmp::Reverse(*key));
-            ranks
-        })
-        .await
+            match mrhandy.fetch_member_roles(user_id).await {
+                Ok(member) => member,
+                Err(err) => {
+                    eprintln!("Discord lookup of {} failed: {:?}", user_id, err);
+                    if err.is_unknown_member() {
+                        return Err("Not a member of Discord server");
+                    }
+                    let saved = saved.ok_or("Can't look up Discord member")?;
+                    eprintln!(
+                        "Using roles of {} saved {} s ago",
+                        user_id,
+                        unix_now().saturating_sub(saved.fetched)
+                    );
+                    return Ok(saved);
+                }
+            }
+        }
+    };
+
+    let roles = CachedRoles {
+        name: member.name,
+        nick: member.nick,
+        roles: member.roles,
+        fetched: unix_now(),
+    };
+    data.rank_cache.put(user_id, roles.clone(), ttl);
+    let root = data.sled_db.root.clone();
+    let saved = roles.clone();
+    let res = blocking(move || {
+        root.trunk(user_id, None, UserTrunk::default())
+            .set_cached_roles(&saved)
+    })
+    .await;
+    if let Err(err) = res {
+        eprintln!("Can't save roles of {}: {:?}", user_id, err);
+    }
+    Ok(roles)
+}
+
+fn local_permissions(data: &AppState) -> Permissions {
+    let mut permissions = Permissions::default();
+    permissions.grant(&data.config.authkey.login_permissions);
+    permissions
+}
+
+pub async fn get_permissions(
+    data: Arc<AppState>,
+    user_id: u64,
+) -> Result<Permissions, &'static str> {
+    if local_char_id(user_id).is_some() {
+        return Ok(local_permissions(&data));
+    }
+    let roles = get_member_roles(&data, user_id).await?;
+    Ok(member_permissions(
+        data.config.discord.as_ref().expect("Discord config"),
+        &roles.roles,
+    ))
 }
 
 pub async fn get_user_record(data: &AppState, user_id: u64) -> Result<UserRecord, &'static str> {
-    data.mrhandy
-        .as_ref()
-        .expect("Discord config")
-        .with_guild_member(user_id, |guild, member| {
-            let mut ranks = mrhandy::MrHandy::get_roles(
-                guild,
-                member,
-                role_to_rank(&data.config.discord.as_ref().expect("Discord config").roles),
-            );
-            ranks.sort_by_key(|key| std::cmp::Reverse(*key));
-            let (name, nick) = mrhandy::MrHandy::get_name_nick(member);
-            UserRecord { name, nick, ranks }
-        })
-        .await
+    if let Some(char_id) = local_char_id(user_id) {
+        return Ok(UserRecord {
+            name: format!("Character {}", char_id),
+            nick: None,
+            permissions: local_permissions(data),
+        });
+    }
+    let roles = get_member_roles(data, user_id).await?;
+    let permissions = member_permissions(
+        data.config.discord.as_ref().expect("Discord config"),
+        &roles.roles,
+    );
+    Ok(UserRecord {
+        name: roles.name,
+        nick: roles.nick,
+        permissions,
+    })
 }
 
 pub struct UserRecord {
-    pub name: FixedString,
-    pub nick: Option<FixedString>,
-    pub ranks: Vec<Rank>,
+    pub name: String,
+    pub nick: Option<String>,
+    pub permissions: Permissions,
 }
 
-#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Ord, Eq)]
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Ord, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Rank {
     Unknown,
     Player,
@@ -54,29 +183,127 @@ pub enum Rank {
     Admin,
 }
 
-fn role_to_rank(config: &crate::config::Roles) -> impl '_ + Fn(&mrhandy::Role) -> Rank {
-    move |role| {
-        if role.name == config.player {
-            Rank::Player
-        } else if role.name == config.gamemaster {
-            Rank::GameMaster
-        } else if role.name == config.developer {
-            Rank::Developer
-        } else if role.name == config.admin {
-            Rank::Admin
-        } else {
-            Rank::Unknown
+fn role_to_rank(config: &crate::config::Roles, id: u64, name: &str) -> Rank {
+    if config.player.matches(id, name) {
+        Rank::Player
+    } else if config.gamemaster.matches(id, name) {
+        Rank::GameMaster
+    } else if config.developer.matches(id, name) {
+        Rank::Developer
+    } else if config.admin.matches(id, name) {
+        Rank::Admin
+    } else {
+        Rank::Unknown
+    }
+}
+
+/// Union of permissions of member's ranks and roles
+fn member_permissions(config: &crate::config::Discord, roles: &[(u64, String)]) -> Permissions {
+    let mut permissions = Permissions::default();
+    for (id, name) in roles {
+        let rank = role_to_rank(&config.roles, *id, name);
+        grant_rank(&mut permissions, Some(config), rank);
+        let id = id.to_string();
+        for key in [&id, name] {
+            if let Some(granted) = config.role_permissions.get(key) {
+                permissions.grant(granted);
+            }
         }
     }
+    permissions
+}
+
+fn grant_rank(permissions: &mut Permissions, config: Option<&crate::config::Discord>, rank: Rank) {
+    match rank
+        .config_key()
+        .and_then(|key| config?.permissions.get(key))
+    {
+        Some(granted) => permissions.grant(granted),
+        None => permissions.grant(rank.default_permissions().iter().copied()),
+    }
 }
 
+/// Permissions of a member who has only this rank
+pub fn rank_permissions(data: &AppState, rank: Rank) -> Permissions {
+    let mut permissions = Permissions::default();
+    grant_rank(&mut permissions, data.config.discord.as_ref(), rank);
+    permissions
+}
+
+#[derive(Clone)]
 pub struct Member {
     pub id: u64,
-    pub ranks: Vec<Rank>,
+    pub permissions: Permissions,
+    pub settings: UserSettings,
+    /// Admin who looks at the site as this member
+    pub impersonator: Option<u64>,
+    /// Authenticated with API token instead of the browser session
+    pub by_token: bool,
 }
 
+/// Logged in member, the same one `restrict` rules have checked
+impl FromRequest for Member {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        extract_member(req)
+            .and_then(|member| async move {
+                member.ok_or_else(|| access_denied("Not logged in")().into())
+            })
+            .boxed_local()
+    }
+}
+
+pub async fn get_user_settings(
+    data: &AppState,
+    user_id: u64,
+) -> Result<UserSettings, VersionedError> {
+    let root = data.sled_db.root.clone();
+    blocking(move || {
+        root.trunk(user_id, None, UserTrunk::default())
+            .get_settings()
+    })
+    .await
+}
+
+/// Secret from `Authorization: Bearer <token>` header
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix("Bearer ")
+        .map(|secret| secret.trim().to_owned())
+}
+
+async fn authenticate_token(data: &AppState, secret: String) -> Result<ApiToken, actix_web::Error> {
+    let root = data.sled_db.root.clone();
+    let token = blocking(move || token::authenticate(&root, &secret))
+        .await
+        .map_err(internal_error)?;
+    Ok(token.ok_or_else(access_denied("Invalid API token"))?)
+}
+
+/// Member from API token or session, token limits permissions to its scopes.
+/// Found member is kept in request extensions, so handlers see the one `restrict` has checked.
 pub fn extract_member(
     req: &HttpRequest,
+) -> impl Future<Output = Result<Option<Member>, actix_web::Error>> {
+    let cached = req.extensions().get::<Member>().cloned();
+    let req = req.clone();
+    async move {
+        if let Some(member) = cached {
+            return Ok(Some(member));
+        }
+        let member = find_member(&req).await?;
+        if let Some(member) = &member {
+            req.extensions_mut().insert(member.clone());
+        }
+        Ok(member)
+    }
+}
+
+fn find_member(
+    req: &HttpRequest,
 ) -> impl Future<Output = Result<Option<Member>, actix_web::Error>> {
     let data = req
         .app_data()
@@ -84,16 +311,70 @@ pub fn extract_member(
         .map(Data::<AppState>::into_inner)
         .ok_or("No AppState data")
         .map_err(internal_error);
+    let bearer = bearer_token(req);
     let session = req.get_session();
     let user_id = get_user_id(&session);
+    let impersonation = get_impersonation(&session);
 
     async move {
-        match user_id {
-            Some(id) => {
-                let ranks = get_ranks(data?, id).await.map_err(internal_error)?;
-                Ok(Some(Member { id, ranks }))
+        let (data, id, scopes) = match (bearer, user_id) {
+            (Some(secret), _) => {
+                let data = data?;
+                let token = authenticate_token(&data, secret).await?;
+                (data, token.user_id, Some(token.scopes))
             }
-            None => Ok(None),
+            (None, Some(id)) => match impersonation {
+                Some(impersonation) => {
+                    return impersonated_member(data?, id, impersonation.target)
+                        .await
+                        .map(Some);
+                }
+                None => (data?, id, None),
+            },
+            (None, None) => return Ok(None),
+        };
+        let settings = get_user_settings(&data, id).await.map_err(internal_error)?;
+        let mut permissions = get_permissions(data, id).await.map_err(internal_error)?;
+        if let Some(scopes) = &scopes {
+            permissions = permissions.limit(scopes);
         }
+        Ok(Some(Member {
+            id,
+            permissions,
+            settings,
+            impersonator: None,
+            by_token: scopes.is_some(),
+        }))
+    }
+}
+
+/// Member as seen by the admin, rank is tried on admin's own account
+async fn impersonated_member(
+    data: Arc<AppState>,
+    admin_id: u64,
+    target: ImpersonationTarget,
+) -> Result<Member, actix_web::Error> {
+    let admin = get_permissions(data.clone(), admin_id)
+        .await
+        .map_err(internal_error)?;
+    if !admin.allows(permission::IMPERSONATE) {
+        return Err(access_denied("No permission to impersonate, exit impersonation")().into());
     }
+    let (id, permissions) = match target {
+        ImpersonationTarget::User(user_id) => (
+            user_id,
+            get_permissions(data.clone(), user_id)
+                .await
+                .map_err(internal_error)?,
+        ),
+        ImpersonationTarget::Rank(rank) => (admin_id, rank_permissions(&data, rank)),
+    };
+    let settings = get_user_settings(&data, id).await.map_err(internal_error)?;
+    Ok(Member {
+        id,
+        permissions,
+        settings,
+        impersonator: Some(admin_id),
+        by_token: false,
+    })
 }
@@ -0,0 +1,122 @@
+use serde::Serialize;
+
+use super::*;
+use crate::{
+    database::{
+        session::{self, SessionInfo},
+        VersionedError,
+    },
+    templates,
+    utils::blocking,
+};
+
+const SESSIONS_URL: &str = "/meta/sessions";
+
+#[derive(Serialize)]
+struct SessionsPage {
+    user_id: u64,
+    sessions: Vec<SessionInfo>,
+}
+
+pub async fn sessions(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    session: Session,
+    impersonating: Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = match extract_member(&req).await? {
+        Some(member) => member.id,
+        None => {
+            session.insert(LOCATION_AFTER_AUTH, SESSIONS_URL)?;
+            return login(data, session).await;
+        }
+    };
+    let body = blocking(move || {
+        let page = SessionsPage {
+            user_id,
+            sessions: session::user_sessions(&data.sled_db.root, user_id)
+                .map_err(SessionsError::Versioned)?,
+        };
+        templates::render(
+            "user_sessions.html",
+            &page,
+            impersonating.render_config(&data.config.host),
+        )
+        .map_err(SessionsError::Template)
+    })
+    .await
+    .map_err(internal_error)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+pub async fn revoke_session(
+    data: web::Data<AppState>,
+    member: Member,
+    path: web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = member.id;
+    let id = path.into_inner();
+    let root = data.sled_db.root.clone();
+    blocking(move || session::revoke_session(&root, user_id, &id))
+        .await
+        .map_err(|err| match err {
+            VersionedError::NotFound | VersionedError::AccessDenied => {
+                access_denied("Unknown session")().into()
+            }
+            err => internal_error(err),
+        })?;
+    Ok(HttpResponse::SeeOther()
+        .append_header((header::LOCATION, SESSIONS_URL))
+        .finish())
+}
+
+/// Logs out everywhere, including the current browser
+pub async fn revoke_all_sessions(
+    data: web::Data<AppState>,
+    member: Member,
+    session: Session,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = member.id;
+    let root = data.sled_db.root.clone();
+    blocking(move || session::revoke_user_sessions(&root, user_id))
+        .await
+        .map_err(internal_error)?;
+    session.purge();
+    Ok(HttpResponse::SeeOther()
+        .append_header((header::LOCATION, "/"))
+        .finish())
+}
+
+pub async fn admin_revoke_sessions(
+    data: web::Data<AppState>,
+    path: web::Path<u64>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = path.into_inner();
+    let root = data.sled_db.root.clone();
+    let count = blocking(move || session::revoke_user_sessions(&root, user_id))
+        .await
+        .map_err(internal_error)?;
+    println!("Revoked {} sessions of {}", count, user_id);
+    Ok(HttpResponse::SeeOther()
+        .append_header((
+            header::LOCATION,
+            format!("/admin/user/{}/settings", user_id),
+        ))
+        .finish())
+}
+
+#[derive(Debug)]
+enum SessionsError {
+    Versioned(VersionedError),
+    Template(templates::TemplatesError),
+    Blocking,
+}
+
+impl From<actix_web::error::BlockingError> for SessionsError {
+    fn from(_err: actix_web::error::BlockingError) -> Self {
+        SessionsError::Blocking
+    }
+}
@@ -0,0 +1,165 @@
+use serde::Serialize;
+
+use super::*;
+use crate::{
+    database::{ownership::owned_characters, UserSettings, UserTrunk, VersionedError},
+    templates,
+    utils::blocking,
+};
+
+const LANGUAGES: [&str; 2] = ["ru", "en"];
+
+#[derive(Deserialize)]
+pub struct SettingsForm {
+    #[serde(default)]
+    language: String,
+    notifications: Option<String>,
+    max_characters: Option<String>,
+}
+
+impl SettingsForm {
+    fn apply(&self, settings: &mut UserSettings, admin: bool) -> Result<(), &'static str> {
+        settings.language = match self.language.as_str() {
+            "" => None,
+            lang if LANGUAGES.contains(&lang) => Some(lang.to_owned()),
+            _ => return Err("Unknown language"),
+        };
+        settings.notifications = self.notifications.is_some();
+        if admin {
+            settings.max_characters = match self.max_characters.as_deref().map(str::trim) {
+                None | Some("") => None,
+                Some(max) => Some(max.parse().map_err(|_| "Limit should be a number")?),
+            };
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct SettingsPage<'a> {
+    user_id: u64,
+    settings: &'a UserSettings,
+    characters: Vec<u32>,
+    languages: &'static [&'static str],
+    admin: bool,
+}
+
+pub async fn settings(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    session: Session,
+    impersonating: Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    match extract_member(&req).await? {
+        Some(member) => render_settings(data, member.id, false, impersonating).await,
+        None => {
+            session.insert(LOCATION_AFTER_AUTH, "/meta/settings")?;
+            login(data, session).await
+        }
+    }
+}
+
+pub async fn update_settings(
+    data: web::Data<AppState>,
+    member: Member,
+    form: web::Form<SettingsForm>,
+) -> actix_web::Result<HttpResponse> {
+    save_settings(&data, member.id, form.into_inner(), false).await?;
+    Ok(HttpResponse::SeeOther()
+        .append_header((header::LOCATION, "/meta/settings"))
+        .finish())
+}
+
+pub async fn admin_settings(
+    data: web::Data<AppState>,
+    path: web::Path<u64>,
+    impersonating: Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    render_settings(data, path.into_inner(), true, impersonating).await
+}
+
+pub async fn admin_update_settings(
+    data: web::Data<AppState>,
+    path: web::Path<u64>,
+    form: web::Form<SettingsForm>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = path.into_inner();
+    save_settings(&data, user_id, form.into_inner(), true).await?;
+    Ok(HttpResponse::SeeOther()
+        .append_header((
+            header::LOCATION,
+            format!("/admin/user/{}/settings", user_id),
+        ))
+        .finish())
+}
+
+async fn save_settings(
+    data: &AppState,
+    user_id: u64,
+    form: SettingsForm,
+    admin: bool,
+) -> actix_web::Result<()> {
+    let mut settings = get_user_settings(data, user_id)
+        .await
+        .map_err(internal_error)?;
+    form.apply(&mut settings, admin)
+        .map_err(|err| bad_request(err)())?;
+
+    let root = data.sled_db.root.clone();
+    blocking(move || {
+        root.trunk(user_id, None, UserTrunk::default())
+            .set_settings(&settings)
+    })
+    .await
+    .map_err(internal_error)
+}
+
+async fn render_settings(
+    data: web::Data<AppState>,
+    user_id: u64,
+    admin: bool,
+    impersonating: Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let body = blocking(move || {
+        let root = &data.sled_db.root;
+        let settings = root
+            .trunk(user_id, None, UserTrunk::default())
+            .get_settings()
+            .map_err(SettingsError::Versioned)?;
+        let page = SettingsPage {
+            user_id,
+            settings: &settings,
+            characters: owned_characters(root, user_id).map_err(SettingsError::Versioned)?,
+            languages: &LANGUAGES,
+            admin,
+        };
+        templates::render(
+            "user_settings.html",
+            &page,
+            templates::RenderConfig {
+                settings: Some(&settings),
+                ..impersonating.render_config(&data.config.host)
+            },
+        )
+        .map_err(SettingsError::Template)
+    })
+    .await
+    .map_err(internal_error)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+#[derive(Debug)]
+enum SettingsError {
+    Versioned(VersionedError),
+    Template(templates::TemplatesError),
+    Blocking,
+}
+
+impl From<actix_web::error::BlockingError> for SettingsError {
+    fn from(_err: actix_web::error::BlockingError) -> Self {
+        SettingsError::Blocking
+    }
+}
@@ -0,0 +1,152 @@
+use serde::Serialize;
+
+use super::{rank::get_permissions, *};
+use crate::{
+    database::{
+        token::{self, TokenInfo, MAX_NAME_LEN},
+        VersionedError,
+    },
+    templates,
+    utils::blocking,
+};
+
+const TOKENS_URL: &str = "/meta/tokens";
+
+#[derive(Deserialize)]
+pub struct TokenForm {
+    #[serde(default)]
+    name: String,
+    /// Comma separated permissions
+    #[serde(default)]
+    scopes: String,
+}
+
+#[derive(Serialize)]
+struct TokensPage<'a> {
+    user_id: u64,
+    tokens: Vec<TokenInfo>,
+    permissions: Vec<&'a str>,
+    /// Secret of just created token, it's shown only once
+    created: Option<String>,
+}
+
+pub async fn tokens(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    session: Session,
+    impersonating: Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    match extract_member(&req).await? {
+        Some(member) => render_tokens(data, member.id, None, impersonating).await,
+        None => {
+            session.insert(LOCATION_AFTER_AUTH, TOKENS_URL)?;
+            login(data, session).await
+        }
+    }
+}
+
+/// Tokens can be created only from the browser session, never with another token
+pub async fn create_token(
+    data: web::Data<AppState>,
+    member: Member,
+    form: web::Form<TokenForm>,
+    impersonating: Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = session_member(&member)?;
+    let TokenForm { name, scopes } = form.into_inner();
+    let name = name.trim().to_owned();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(bad_request("Token name should be 1-64 characters long")().into());
+    }
+    let scopes: Vec<String> = scopes
+        .split(',')
+        .map(str::trim)
+        .filter(|scope| !scope.is_empty())
+        .map(String::from)
+        .collect();
+    if scopes.is_empty() {
+        return Err(bad_request("Token should have at least one scope")().into());
+    }
+    if !scopes.iter().all(|scope| member.permissions.allows(scope)) {
+        return Err(access_denied("Token can't have permissions you don't have")().into());
+    }
+
+    let root = data.sled_db.root.clone();
+    let (secret, _id) = blocking(move || token::create_token(&root, user_id, name, scopes))
+        .await
+        .map_err(internal_error)?;
+    render_tokens(data, user_id, Some(secret), impersonating).await
+}
+
+pub async fn revoke_token(
+    data: web::Data<AppState>,
+    member: Member,
+    path: web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = session_member(&member)?;
+    let id = path.into_inner();
+    let root = data.sled_db.root.clone();
+    blocking(move || token::revoke_token(&root, user_id, &id))
+        .await
+        .map_err(|err| match err {
+            VersionedError::NotFound | VersionedError::AccessDenied => {
+                access_denied("Unknown token")().into()
+            }
+            err => internal_error(err),
+        })?;
+    Ok(HttpResponse::SeeOther()
+        .append_header((header::LOCATION, TOKENS_URL))
+        .finish())
+}
+
+fn session_member(member: &Member) -> actix_web::Result<u64> {
+    if member.by_token {
+        return Err(access_denied("Tokens can be managed only from the browser session")().into());
+    }
+    Ok(member.id)
+}
+
+async fn render_tokens(
+    data: web::Data<AppState>,
+    user_id: u64,
+    created: Option<String>,
+    impersonating: Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let permissions = get_permissions(data.clone().into_inner(), user_id)
+        .await
+        .map_err(internal_error)?;
+    let body = blocking(move || {
+        let page = TokensPage {
+            user_id,
+            tokens: token::user_tokens(&data.sled_db.root, user_id)
+                .map_err(TokensError::Versioned)?,
+            permissions: permissions.iter().collect(),
+            created,
+        };
+        templates::render(
+            "user_tokens.html",
+            &page,
+            impersonating.render_config(&data.config.host),
+        )
+        .map_err(TokensError::Template)
+    })
+    .await
+    .map_err(internal_error)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+#[derive(Debug)]
+enum TokensError {
+    Versioned(VersionedError),
+    Template(templates::TemplatesError),
+    Blocking,
+}
+
+impl From<actix_web::error::BlockingError> for TokensError {
+    fn from(_err: actix_web::error::BlockingError) -> Self {
+        TokensError::Blocking
+    }
+}
@@ -5,8 +5,9 @@ use std::{collections::BTreeMap, sync::Arc};
 use actix_service::Service;
 use actix_web::{
     body::MessageBody,
-    dev::{ServiceRequest, ServiceResponse},
-    error::InternalError,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    error::{InternalError, QueryPayloadError},
+    http::StatusCode,
     middleware, web, App, HttpRequest, HttpResponse, HttpServer,
 };
 #[cfg(feature = "fo_data")]
@@ -18,16 +19,32 @@ use futures::{
 use oauth2::{basic, EndpointNotSet, EndpointSet};
 use tokio::sync::Mutex;
 
-use self::restrict::restrict;
-use crate::{bridge, config, critters_db::CrittersDb, database::SledDb};
+use self::{
+    meta::permission,
+    restrict::restrict,
+    throttle::{restrict_rate, Throttle},
+};
+use crate::{
+    bridge, config,
+    critters_db::CrittersDb,
+    database::{session, SledDb},
+    palette::Palette,
+    utils::blocking,
+};
 
+mod admin;
 mod avatar;
 mod char_action;
+mod csrf;
 mod dir;
 mod gm;
+mod image;
 mod meta;
+mod moderation;
 mod restrict;
+mod session_store;
 mod stats;
+mod throttle;
 
 #[cfg(feature = "fo_data")]
 mod data;
@@ -35,6 +52,9 @@ mod data;
 mod map_viewer;
 
 const STATIC_PATH: &str = "./static/";
+const SESSION_COOKIE_NAME: &str = "meta-session";
+/// Seconds between removals of expired sessions
+const SESSION_SWEEP_INTERVAL: u64 = 60 * 60;
 
 async fn index(
     _req: HttpRequest,
@@ -43,42 +63,97 @@ async fn index(
 ) -> actix_web::Result<HttpResponse> {
     let body = match meta::get_user_id(&session) {
         Some(user_id) => {
-            let (name_string, max_rank) = match meta::get_user_record(&data, user_id).await {
+            let impersonation = meta::get_impersonation(&session);
+            let record = match impersonation.as_ref().map(|imp| &imp.target) {
+                Some(meta::ImpersonationTarget::User(viewed_id)) => {
+                    meta::get_user_record(&data, *viewed_id).await
+                }
+                Some(meta::ImpersonationTarget::Rank(rank)) => {
+                    meta::get_user_record(&data, user_id)
+                        .await
+                        .map(|mut record| {
+                            record.permissions = meta::rank_permissions(&data, *rank);
+                            record
+                        })
+                }
+                None => meta::get_user_record(&data, user_id).await,
+            };
+            let (name_string, permissions) = match record {
                 Ok(record) => (
                     match &record.nick {
                         Some(nick) => format!(r#"{} ({})"#, record.name, nick),
                         None => format!(r#"{}"#, record.name),
                     },
-                    record.ranks.first().cloned(),
+                    record.permissions,
                 ),
                 Err(err) => (
                     {
                         eprintln!("Index page error: {}", err);
                         r#"<red>error</red>"#.to_string()
                     },
-                    None,
+                    meta::Permissions::default(),
                 ),
             };
-            let maps = if cfg!(feature = "map_viewer") {
-                "<li><a href=\"maps\">maps</a></li>"
+            let private = data
+                .config
+                .paths
+                .privates()
+                .keys()
+                .any(|name| permissions.allows(&permission::private_permission(name)));
+            let links = [
+                (
+                    permissions.allows(permission::VIEW_CLIENTS),
+                    "gm/clients",
+                    "clients",
+                ),
+                (
+                    permissions.allows(permission::VIEW_AVATARS),
+                    "gm/avatars",
+                    "avatars",
+                ),
+                (
+                    permissions.allows(permission::MODERATE_AVATARS),
+                    "gm/avatars/queue",
+                    "avatar queue",
+                ),
+                (private, "private/", "private"),
+                (
+                    cfg!(feature = "map_viewer") && permissions.allows(permission::VIEW_MAPS),
+                    "maps",
+                    "maps",
+                ),
+                (
+                    permissions.allows(permission::CHECK_DATABASE),
+                    "admin/check",
+                    "database check",
+                ),
+            ];
+            let items: String = links
+                .iter()
+                .filter(|(allowed, _, _)| *allowed)
+                .map(|(_, url, name)| format!(r#"<li><a href="{}">{}</a></li>"#, url, name))
+                .collect();
+            let menu = if items.is_empty() {
+                String::new()
             } else {
-                ""
+                format!("<h1>Menu:</h1><ul>{}</ul>", items)
+            };
+            let banner = match &impersonation {
+                Some(impersonation) => format!(
+                    r#"<div class="impersonation-banner">Viewing as {}, read only <form method="post" action="{}">{}<input type="submit" value="Exit"></form></div>"#,
+                    impersonation.target,
+                    meta::IMPERSONATION_EXIT_URL,
+                    csrf::csrf_input(&session)
+                ),
+                None => String::new(),
             };
-            let menu = max_rank
-                .filter(|rank| *rank >= meta::Rank::GameMaster)
-                .map_or(String::new(), |_| {
-                    format!(
-                        "<h1>Menu:</h1><ul>\
-                         <li><a href=\"gm/clients\">clients</a></li>\
-                         <li><a href=\"private/\">private</a></li>\
-                         {}\
-                         </ul>",
-                        maps
-                    )
-                });
             format!(
-                r#"User: {} <a href="/meta/logout">Logout</a>{}"#,
-                name_string, menu
+                r#"{}User: {} <a href="/meta/settings">Settings</a> <a href="/meta/tokens">API tokens</a> <a href="/meta/sessions">Sessions</a> <form method="post" action="{}" style="display: inline">{}<input type="submit" value="Logout"></form>{}"#,
+                banner,
+                name_string,
+                meta::LOGOUT_URL,
+                csrf::csrf_input(&session),
+                menu
             )
         }
         None => r#"<a href="/meta/login">Login</a>"#.to_string(),
@@ -135,6 +210,13 @@ pub struct AppState {
     items: Option<Arc<FoItems>>,
     reqwest: reqwest::Client,
     pub(crate) server_status: Mutex<bridge::Status>,
+    avatar_cache: avatar::AvatarCache,
+    palette: Option<Arc<Palette>>,
+    rank_cache: meta::RankCache,
+    throttles: throttle::Throttles,
+    /// Roles of users from the fake OAuth provider, by user id
+    #[cfg(test)]
+    fake_members: std::collections::HashMap<u64, crate::database::CachedRoles>,
 }
 
 #[cfg(feature = "fo_proto_format")]
@@ -185,6 +267,16 @@ impl AppState {
 
         let sled_db = SledDb::new(db);
         let bridge = bridge::Bridge::new();
+        let avatar_cache = avatar::AvatarCache::new(config.avatar.cache_size);
+        let throttles = throttle::Throttles::new(&config.rate_limit);
+        let palette = config
+            .paths
+            .palette
+            .as_ref()
+            .map(|path| Arc::new(Palette::load(path).expect("Palette loading")));
+        if config.avatar.quantize && palette.is_none() {
+            panic!("Avatar quantization needs paths.palette");
+        }
 
         let redirect = config.host.web_url("/meta/auth");
         let oauth = config
@@ -213,6 +305,12 @@ impl AppState {
             items: def.items.map(Arc::new),
             reqwest,
             server_status: Mutex::new(bridge::Status::new()),
+            avatar_cache,
+            palette,
+            rank_cache: meta::RankCache::default(),
+            throttles,
+            #[cfg(test)]
+            fake_members: Default::default(),
         }
     }
 
@@ -245,15 +343,12 @@ pub fn oauth2_client(
     redirect: String,
 ) -> Result<Oauth2Client, Box<dyn std::error::Error>> {
     use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
-    let client = oauth2::Client::new(
-        ClientId::new(config.client_id.clone())
-    ).set_client_secret(ClientSecret::new(config.secret.clone()))
-    .set_auth_uri(AuthUrl::new("https://discordapp.com/api/oauth2/authorize".to_string())?)
-    .set_token_uri(TokenUrl::new(
-        "https://discordapp.com/api/oauth2/token".to_string(),
-    )?)
-    // Set the URL the user will be redirected to after the authorization process.
-    .set_redirect_uri(RedirectUrl::new(redirect)?);
+    let client = oauth2::Client::new(ClientId::new(config.client_id.clone()))
+        .set_client_secret(ClientSecret::new(config.secret.clone()))
+        .set_auth_uri(AuthUrl::new(config.authorize_url.clone())?)
+        .set_token_uri(TokenUrl::new(config.token_url.clone())?)
+        // Set the URL the user will be redirected to after the authorization process.
+        .set_redirect_uri(RedirectUrl::new(redirect)?);
     Ok(client)
 }
 
@@ -283,90 +378,7 @@ async fn run_async(mut state: AppState) {
 
     let web_server = HttpServer::new({
         let state = state.clone();
-        move || {
-            let cookies = actix_session::SessionMiddleware::builder(
-                actix_session::storage::CookieSessionStore::default(),
-                state.config.session.cookie_key(),
-            )
-            .cookie_secure(false)
-            .cookie_name("meta-session".into())
-            .build();
-            let app = App::new()
-                .app_data(state.clone())
-                .wrap(middleware::Compress::default())
-                .wrap(middleware::Logger::default())
-                .wrap_fn(restrict_web)
-                .wrap(cookies)
-                .service(web::resource("/").route(web::get().to(index)))
-                .service(
-                    web::scope("/meta")
-                        .service(web::resource("/login").route(web::get().to(meta::login)))
-                        .service(web::resource("/logout").route(web::get().to(meta::logout)))
-                        .service(web::resource("/auth").route(web::get().to(meta::auth))),
-                )
-                .service(
-                    web::scope("/gm")
-                        .wrap(restrict(meta::restrict_gm))
-                        .service(web::resource("/clients").route(web::get().to(gm::clients)))
-                        .service(
-                            web::resource("/client/{client}").route(web::get().to(stats::gm_stats)),
-                        ),
-                )
-                .service(
-                    web::scope("/char/{id}")
-                        .service(
-                            web::scope("/edit")
-                                .wrap(restrict(meta::restrict_ownership))
-                                .service(
-                                    web::resource("/avatar")
-                                        .route(web::get().to(avatar::edit))
-                                        .route(web::post().to(avatar::upload)),
-                                ),
-                        )
-                        .service(
-                            web::scope("/action")
-                                .wrap(restrict(meta::restrict_ownership))
-                                .service(
-                                    web::resource("/start_game")
-                                        .route(web::get().to(char_action::start_game)),
-                                ),
-                        )
-                        .service(web::resource("/avatar").route(web::get().to(avatar::show))),
-                )
-                .service(actix_files::Files::new("/static", STATIC_PATH))
-                .service({
-                    let mut private = web::scope("/private")
-                        .wrap(restrict(meta::restrict_gm))
-                        .service(web::resource("/").route(web::get().to(list_privates)));
-                    let name_path = state.config.paths.privates();
-                    for (name, path) in name_path {
-                        private = private.service(
-                            actix_files::Files::new(&format!("/{}", name), path)
-                                .show_files_listing()
-                                .files_listing_renderer(dir::directory_listing),
-                        );
-                    }
-                    private
-                });
-            #[cfg(feature = "map_viewer")]
-            let app = app.service(
-                web::scope("/maps")
-                    .wrap(restrict(meta::restrict_gm))
-                    //.service(web::resource("/tilemap").route(web::get().to(map_viewer::tilemap))),
-                    .service(web::resource("/{path:.+}").route(web::get().to(map_viewer::view)))
-                    .service(web::resource("").route(web::get().to(map_viewer::list))),
-            );
-            #[cfg(feature = "fo_data")]
-            let app = app.service(
-                web::resource("/data/{path:.+}")
-                    .wrap(restrict(meta::restrict_gm))
-                    .route(web::get().to(data::get)),
-            );
-            app
-            //.service(
-            //    web::resource("/{crid}").route(web::get().to_async(stats::gm_stats))
-            //)
-        }
+        move || web_app(&state)
     })
     .server_hostname(state.config.host.web.domain_port());
 
@@ -396,7 +408,8 @@ async fn run_async(mut state: AppState) {
                 .service(actix_files::Files::new("/static", STATIC_PATH))
                 .service(
                     web::scope("/char/{id}")
-                        .service(web::resource("/avatar").route(web::get().to(avatar::show))),
+                        .service(web::resource("/avatar").route(web::get().to(avatar::show)))
+                        .service(web::resource("/image/{slot}").route(web::get().to(image::show))),
                 )
         }
     })
@@ -412,6 +425,7 @@ async fn run_async(mut state: AppState) {
         web_server.map_err(RuntimeError::Io).boxed(),
         file_server.map_err(RuntimeError::Io).boxed(),
     ];
+    futs.push(session_sweeper(state.clone()).boxed());
     if let Some(serenity_client) = serenity_client.as_mut() {
         futs.push(
             serenity_client
@@ -426,6 +440,271 @@ async fn run_async(mut state: AppState) {
     println!("Stopping... Result: {:?}", res);
 }
 
+/// Pages and API of the web host, without the servers around it
+fn web_app(
+    state: &web::Data<AppState>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let cookies = actix_session::SessionMiddleware::builder(
+        session_store::SledSessionStore::new(
+            state.sled_db.root.clone(),
+            state.config.session.ttl(),
+        ),
+        state.config.session.cookie_key(),
+    )
+    .cookie_secure(state.config.cookie_secure())
+    .cookie_name(SESSION_COOKIE_NAME.into())
+    .build();
+    let app = App::new()
+        .app_data(state.clone())
+        .wrap(middleware::Compress::default())
+        .wrap(middleware::Logger::default())
+        .wrap_fn(restrict_web)
+        .wrap_fn(meta::impersonation_guard)
+        .wrap(csrf::Csrf::new(state.config.cookie_secure()))
+        .wrap(cookies)
+        .service(web::resource("/").route(web::get().to(index)))
+        .service(
+            web::scope("/meta")
+                .service(
+                    web::resource("/login")
+                        .wrap(restrict(restrict_rate(Throttle::Login)))
+                        .route(web::get().to(meta::login)),
+                )
+                .service(
+                    web::resource("/login/discord")
+                        .wrap(restrict(restrict_rate(Throttle::Login)))
+                        .route(web::get().to(meta::discord_login)),
+                )
+                .service(
+                    web::resource("/login/authkey")
+                        .route(web::get().to(meta::authkey_form))
+                        .route(web::post().to(meta::authkey_login)),
+                )
+                .service(web::resource("/logout").route(web::post().to(meta::logout)))
+                .service(
+                    web::resource("/auth")
+                        .wrap(restrict(restrict_rate(Throttle::Auth)))
+                        .route(web::get().to(meta::auth)),
+                )
+                .service(
+                    web::resource("/settings")
+                        .route(web::get().to(meta::settings))
+                        .route(web::post().to(meta::update_settings)),
+                )
+                .service(
+                    web::resource("/tokens")
+                        .route(web::get().to(meta::tokens))
+                        .route(web::post().to(meta::create_token)),
+                )
+                .service(
+                    web::resource("/tokens/{id}/revoke").route(web::post().to(meta::revoke_token)),
+                )
+                .service(web::resource("/sessions").route(web::get().to(meta::sessions)))
+                .service(
+                    web::resource("/sessions/revoke_all")
+                        .route(web::post().to(meta::revoke_all_sessions)),
+                )
+                .service(
+                    web::resource("/sessions/{id}/revoke")
+                        .route(web::post().to(meta::revoke_session)),
+                )
+                .service(
+                    web::resource("/impersonate/exit")
+                        .route(web::post().to(meta::exit_impersonation)),
+                ),
+        )
+        .service(
+            web::scope("/gm")
+                .service(
+                    web::resource("/clients")
+                        .wrap(restrict(meta::restrict_permission(
+                            permission::VIEW_CLIENTS,
+                        )))
+                        .route(web::get().to(gm::clients)),
+                )
+                .service(
+                    web::resource("/client/{client}")
+                        .wrap(restrict(meta::restrict_permission(
+                            permission::VIEW_CLIENTS,
+                        )))
+                        .route(web::get().to(stats::gm_stats)),
+                )
+                .service(
+                    web::resource("/avatars")
+                        .wrap(restrict(meta::restrict_permission(
+                            permission::VIEW_AVATARS,
+                        )))
+                        .route(web::get().to(gm::avatars)),
+                )
+                .service(
+                    web::scope("/avatars")
+                        .wrap(restrict(meta::restrict_permission(
+                            permission::MODERATE_AVATARS,
+                        )))
+                        .service(web::resource("/queue").route(web::get().to(moderation::queue)))
+                        .service(
+                            web::resource("/{id}/{ver}/preview")
+                                .route(web::get().to(moderation::preview)),
+                        )
+                        .service(
+                            web::resource("/{id}/{ver}/approve")
+                                .route(web::post().to(moderation::approve)),
+                        )
+                        .service(
+                            web::resource("/{id}/{ver}/reject")
+                                .route(web::post().to(moderation::reject)),
+                        ),
+                ),
+        )
+        .service(
+            web::scope("/api/v1")
+                .app_data(web::QueryConfig::default().error_handler(api_query_error))
+                .service(
+                    web::resource("/clients")
+                        .wrap(restrict(meta::restrict_permission(
+                            permission::VIEW_CLIENTS,
+                        )))
+                        .route(web::get().to(gm::api_clients)),
+                ),
+        )
+        .service(
+            web::scope("/admin")
+                .service(
+                    web::resource("/char/{id}/ownership")
+                        .wrap(restrict(meta::restrict_permission(
+                            permission::MANAGE_OWNERSHIP,
+                        )))
+                        .route(web::get().to(admin::ownership))
+                        .route(web::post().to(admin::change_ownership)),
+                )
+                .service(
+                    web::resource("/user/{id}/settings")
+                        .wrap(restrict(meta::restrict_permission(
+                            permission::MANAGE_USERS,
+                        )))
+                        .route(web::get().to(meta::admin_settings))
+                        .route(web::post().to(meta::admin_update_settings)),
+                )
+                .service(
+                    web::resource("/user/{id}/sessions/revoke")
+                        .wrap(restrict(meta::restrict_permission(
+                            permission::MANAGE_USERS,
+                        )))
+                        .route(web::post().to(meta::admin_revoke_sessions)),
+                )
+                .service(
+                    web::resource("/impersonate")
+                        .wrap(restrict(meta::restrict_permission(permission::IMPERSONATE)))
+                        .route(web::get().to(meta::impersonate_page))
+                        .route(web::post().to(meta::start_impersonation)),
+                )
+                .service(
+                    web::resource("/check")
+                        .wrap(restrict(meta::restrict_permission(
+                            permission::CHECK_DATABASE,
+                        )))
+                        .route(web::get().to(admin::check))
+                        .route(web::post().to(admin::repair)),
+                ),
+        )
+        .service(
+            web::scope("/char/{id}")
+                .service(
+                    web::scope("/edit")
+                        .wrap(restrict(meta::restrict_ownership))
+                        .service(
+                            web::resource("/avatar")
+                                .app_data(web::PayloadConfig::new(avatar::MAX_UPLOAD_LEN + 1024))
+                                .wrap(restrict(restrict_rate(Throttle::Upload)))
+                                .route(web::get().to(avatar::edit))
+                                .route(web::post().to(avatar::upload)),
+                        )
+                        .service(
+                            web::resource("/avatar/file")
+                                .wrap(restrict(restrict_rate(Throttle::Upload)))
+                                .route(web::post().to(avatar::upload_multipart)),
+                        )
+                        .service(
+                            web::resource("/image/{slot}")
+                                .wrap(restrict(restrict_rate(Throttle::Upload)))
+                                .route(web::get().to(image::edit))
+                                .route(web::post().to(image::upload)),
+                        ),
+                )
+                .service(
+                    web::scope("/action")
+                        .wrap(restrict(meta::restrict_ownership))
+                        .service(
+                            web::resource("/start_game")
+                                .route(web::get().to(char_action::start_game_form))
+                                .route(web::post().to(char_action::start_game)),
+                        ),
+                )
+                .service(
+                    web::scope("/history")
+                        .wrap(restrict(meta::restrict_owner_or_history))
+                        .service(web::resource("/avatar").route(web::get().to(avatar::history)))
+                        .service(
+                            web::resource("/avatar/{ver}/restore")
+                                .wrap(restrict(meta::restrict_owner_or_moderator))
+                                .route(web::post().to(avatar::restore)),
+                        )
+                        .service(
+                            web::resource("/avatar/{ver}/frm")
+                                .route(web::get().to(avatar::export_frm)),
+                        ),
+                )
+                .service(web::resource("/avatar").route(web::get().to(avatar::show)))
+                .service(web::resource("/image/{slot}").route(web::get().to(image::show))),
+        )
+        .service(actix_files::Files::new("/static", STATIC_PATH))
+        .service({
+            let mut private = web::scope("/private")
+                .service(web::resource("/").route(web::get().to(list_privates)));
+            let name_path = state.config.paths.privates();
+            for (name, path) in name_path {
+                private = private.service(
+                    web::scope(&format!("/{}", name))
+                        .wrap(restrict(meta::restrict_permission(
+                            permission::private_permission(name),
+                        )))
+                        .service(
+                            actix_files::Files::new("", path)
+                                .show_files_listing()
+                                .files_listing_renderer(dir::directory_listing),
+                        ),
+                );
+            }
+            private
+        });
+    #[cfg(feature = "map_viewer")]
+    let app = app.service(
+        web::scope("/maps")
+            .wrap(restrict(meta::restrict_permission(permission::VIEW_MAPS)))
+            //.service(web::resource("/tilemap").route(web::get().to(map_viewer::tilemap))),
+            .service(web::resource("/{path:.+}").route(web::get().to(map_viewer::view)))
+            .service(web::resource("").route(web::get().to(map_viewer::list))),
+    );
+    #[cfg(feature = "fo_data")]
+    let app = app.service(
+        web::resource("/data/{path:.+}")
+            .wrap(restrict(meta::restrict_permission(permission::VIEW_DATA)))
+            .route(web::get().to(data::get)),
+    );
+    app
+    //.service(
+    //    web::resource("/{crid}").route(web::get().to_async(stats::gm_stats))
+    //)
+}
+
 async fn status_updater(state: web::Data<AppState>) -> Result<(), RuntimeError> {
     let mut interval = tokio::time::interval(Duration::from_secs(5));
     loop {
@@ -437,6 +716,20 @@ async fn status_updater(state: web::Data<AppState>) -> Result<(), RuntimeError>
     }
 }
 
+/// Removes expired sessions, the store itself only notices them when they are used
+async fn session_sweeper(state: web::Data<AppState>) -> Result<(), RuntimeError> {
+    let mut interval = tokio::time::interval(Duration::from_secs(SESSION_SWEEP_INTERVAL));
+    loop {
+        interval.tick().await;
+        let root = state.sled_db.root.clone();
+        match blocking(move || session::sweep_sessions(&root)).await {
+            Ok(0) => {}
+            Ok(removed) => println!("Removed {} expired sessions", removed),
+            Err(err) => eprintln!("Can't sweep sessions: {:?}", err),
+        }
+    }
+}
+
 #[derive(Debug)]
 enum RuntimeError {
     Io(std::io::Error),
@@ -495,15 +788,46 @@ fn _restrict_files<
     }
 }
 
-async fn list_privates(data: web::Data<AppState>) -> HttpResponse {
+/// Lists only folders the member is allowed to see
+async fn list_privates(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let member = meta::extract_member(&req)
+        .await?
+        .ok_or_else(meta::access_denied("Restricted zone"))?;
     let name_path = data.config.paths.privates();
     let body: String = name_path
         .keys()
+        .filter(|name| {
+            member
+                .permissions
+                .allows(&permission::private_permission(name))
+        })
         .map(|name| format!(r#"<p><a href="{0}">{0}</a></p>"#, name))
         .collect();
-    HttpResponse::Ok()
+    if body.is_empty() {
+        return Err(meta::access_denied("No permission for this restricted zone")().into());
+    }
+    Ok(HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(body)
+        .body(body))
+}
+
+/// Errors of `/api` are in JSON too: `{"error": "..."}`
+fn api_error(status: StatusCode, message: String) -> actix_web::Error {
+    let body = serde_json::json!({ "error": message }).to_string();
+    InternalError::from_response(
+        message,
+        HttpResponse::build(status)
+            .content_type("application/json")
+            .body(body),
+    )
+    .into()
+}
+
+fn api_query_error(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    api_error(StatusCode::BAD_REQUEST, err.to_string())
 }
 
 fn internal_error<D: std::fmt::Debug>(err: D) -> actix_web::Error {
@@ -516,3 +840,233 @@ fn internal_error<D: std::fmt::Debug>(err: D) -> actix_web::Error {
     )
     .into()
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{SocketAddr, TcpListener},
+        path::{Path, PathBuf},
+    };
+
+    use actix_web::{
+        cookie::Cookie,
+        http::{header, StatusCode},
+        test,
+    };
+    use fo_meta_fake_oauth::User;
+
+    use super::*;
+
+    const ADMIN_ID: &str = "100000000000000001";
+    const PLAYER_ID: &str = "100000000000000002";
+
+    fn users() -> Vec<User> {
+        vec![
+            User {
+                roles: vec!["Adm".into()],
+                ..User::new(ADMIN_ID, "admin")
+            },
+            User {
+                nick: Some("Игрок 1".into()),
+                roles: vec!["Игрок".into()],
+                ..User::new(PLAYER_ID, "player")
+            },
+        ]
+    }
+
+    /// `meta_fake_oauth` on a random local port
+    fn start_provider() -> SocketAddr {
+        use fo_meta_fake_oauth::{configure, Config, State};
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Bind fake provider");
+        let addr = listener.local_addr().expect("Fake provider address");
+        let state = web::Data::new(State::new(Config {
+            addr,
+            client_id: "test".into(),
+            secret: "test".into(),
+            users: users(),
+        }));
+        let server =
+            HttpServer::new(move || App::new().app_data(state.clone()).configure(configure))
+                .workers(1)
+                .disable_signals()
+                .listen(listener)
+                .expect("Listen fake provider")
+                .run();
+        actix_rt::spawn(server);
+        addr
+    }
+
+    /// The bot isn't started, ranks of the users come from their roles at the fake provider
+    fn app_state(provider: SocketAddr, save_clients: &Path) -> AppState {
+        let api = format!("http://{}/api", provider);
+        let toml = format!(
+            r#"
+            [host]
+            web = {{ domain = "localhost", port = 8000 }}
+            files = {{ domain = "127.0.0.1", port = 8001 }}
+
+            [paths]
+            save_clients = {save_clients:?}
+            proto_items = ""
+            working_dir = ""
+            private = []
+
+            [discord]
+            main_guild_id = 1
+            oauth2 = {{ client_id = "test", secret = "test", authorize_url = "{api}/oauth2/authorize", token_url = "{api}/oauth2/token", user_info_url = "{api}/users/@me" }}
+            bot = {{ token = "" }}
+            roles = {{ admin = "Adm", developer = "Dev", gamemaster = "GM", player = "Игрок" }}
+
+            [session]
+            cookie_key = "{key}"
+            "#,
+            save_clients = save_clients,
+            api = api,
+            key = base64::encode([7u8; 32]),
+        );
+        let config = config::Config::from_toml(&toml).expect("Test config");
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .expect("Temporary sled db");
+        let mut state = AppDefinition::new(config, db).build();
+        state.fake_members = users()
+            .into_iter()
+            .map(|user| {
+                let roles = crate::database::CachedRoles {
+                    name: user.username,
+                    nick: user.nick,
+                    roles: user.roles.into_iter().map(|name| (0, name)).collect(),
+                    fetched: crate::database::unix_now(),
+                };
+                (user.id.parse().unwrap(), roles)
+            })
+            .collect();
+        state
+    }
+
+    fn get(uri: &str, cookie: Option<&Cookie<'static>>) -> actix_http::Request {
+        let mut req = test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::HOST, "localhost"))
+            .peer_addr("127.0.0.1:40000".parse().unwrap());
+        if let Some(cookie) = cookie {
+            req = req.cookie(cookie.clone());
+        }
+        req.to_request()
+    }
+
+    fn location<B>(res: &ServiceResponse<B>) -> String {
+        res.headers()
+            .get(header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .expect("Location header")
+            .to_owned()
+    }
+
+    fn session_cookie<B>(res: &ServiceResponse<B>) -> Cookie<'static> {
+        res.response()
+            .cookies()
+            .find(|cookie| cookie.name() == SESSION_COOKIE_NAME)
+            .expect("Session cookie")
+            .into_owned()
+    }
+
+    /// Walks through Discord login, returns cookie of the logged in session
+    async fn login<S, B>(app: &S, user_id: &str) -> Cookie<'static>
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    {
+        let res = test::call_service(app, get("/meta/login/discord", None)).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+        let cookie = session_cookie(&res);
+
+        // The provider skips its user picker when the user is in the query
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let res = client
+            .get(format!("{}&user={}", location(&res), user_id))
+            .send()
+            .await
+            .expect("Fake provider authorize");
+        assert_eq!(res.status(), reqwest::StatusCode::FOUND);
+        let redirect: reqwest::Url = res
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .expect("Redirect to /meta/auth");
+        assert_eq!(redirect.path(), "/meta/auth");
+        let auth = format!("/meta/auth?{}", redirect.query().unwrap());
+
+        let res = test::call_service(app, get(&auth, Some(&cookie))).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(location(&res), "/");
+        let renewed = session_cookie(&res);
+        assert_ne!(renewed.value(), cookie.value());
+        renewed
+    }
+
+    async fn status<S, B>(app: &S, uri: &str, cookie: Option<&Cookie<'static>>) -> StatusCode
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    {
+        match test::try_call_service(app, get(uri, cookie)).await {
+            Ok(res) => res.status(),
+            Err(err) => err.as_response_error().status_code(),
+        }
+    }
+
+    async fn index_page<S, B>(app: &S, cookie: &Cookie<'static>) -> String
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let res = test::call_service(app, get("/", Some(cookie))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        String::from_utf8(test::read_body(res).await.to_vec()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_login_ranks() {
+        let save_clients: PathBuf =
+            std::env::temp_dir().join(format!("fo_meta_test_clients_{}", std::process::id()));
+        std::fs::create_dir_all(&save_clients).unwrap();
+        let provider = start_provider();
+        let state = web::Data::new(app_state(provider, &save_clients));
+        let app = test::init_service(web_app(&state)).await;
+
+        assert_eq!(
+            status(&app, "/api/v1/clients", None).await,
+            StatusCode::FORBIDDEN
+        );
+
+        let admin = login(&app, ADMIN_ID).await;
+        let page = index_page(&app, &admin).await;
+        assert!(page.contains("User: admin "));
+        assert!(page.contains("gm/clients"));
+        assert_eq!(
+            status(&app, "/api/v1/clients", Some(&admin)).await,
+            StatusCode::OK
+        );
+        let res =
+            test::call_service(&app, get("/api/v1/clients?ip=1.2.3.0/33", Some(&admin))).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = test::read_body_json(res).await;
+        assert!(error["error"].is_string());
+
+        let player = login(&app, PLAYER_ID).await;
+        let page = index_page(&app, &player).await;
+        assert!(page.contains("User: player (Игрок 1)"));
+        assert!(!page.contains("gm/clients"));
+        assert_eq!(
+            status(&app, "/api/v1/clients", Some(&player)).await,
+            StatusCode::FORBIDDEN
+        );
+
+        std::fs::remove_dir_all(&save_clients).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+use actix_web::{http::header, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::{avatar, image, internal_error, meta, AppState};
+use crate::{
+    database::{
+        moderation::{self, ModerationState, PendingImage},
+        CharTrunk, Leaf, VersionedError,
+    },
+    templates,
+    utils::blocking,
+};
+
+const QUEUE_URL: &str = "/gm/avatars/queue";
+
+#[derive(Debug, Serialize)]
+struct QueuePage {
+    moderation: bool,
+    images: Vec<PendingImage>,
+}
+
+pub async fn queue(
+    data: web::Data<AppState>,
+    impersonating: meta::Impersonating,
+) -> actix_web::Result<HttpResponse> {
+    let body = blocking(move || {
+        let slots: Vec<&str> = data
+            .config
+            .image_slots
+            .iter()
+            .map(|slot| slot.name.as_str())
+            .collect();
+        let page = QueuePage {
+            moderation: data.config.avatar.moderation,
+            images: moderation::pending_images(&data.sled_db.root, &slots)?,
+        };
+        templates::render(
+            "gm_avatar_queue.html",
+            &page,
+            impersonating.render_config(&data.config.host),
+        )
+        .map_err(ModerationError::Template)
+    })
+    .await
+    .map_err(internal_error)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SlotQuery {
+    /// Image slot, avatar if missing
+    slot: Option<String>,
+}
+
+impl SlotQuery {
+    /// Trunk of the slot and its name, unknown slots are rejected
+    fn bark(&self, data: &AppState) -> actix_web::Result<(CharTrunk, Option<String>)> {
+        match &self.slot {
+            None => Ok((CharTrunk::default(), None)),
+            Some(name) => match data.config.image_slot(name) {
+                Some(slot) => Ok((CharTrunk::slot(&slot.name), Some(slot.name.clone()))),
+                None => Err(meta::bad_request("Unknown image slot")().into()),
+            },
+        }
+    }
+}
+
+/// Shows any version of the avatar or slot image, including pending and rejected ones
+pub async fn preview(
+    path: web::Path<(u32, u32)>,
+    query: web::Query<SlotQuery>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let (char_id, ver) = path.into_inner();
+    let (bark, _slot) = query.bark(&data)?;
+    let image = blocking(move || {
+        data.sled_db
+            .root
+            .trunk(char_id, None, bark)
+            .get_image_version(ver)
+    })
+    .await
+    .map_err(internal_error)?;
+
+    Ok(match image {
+        Some(image) => HttpResponse::Ok()
+            .content_type("image/png")
+            .body(bytes::Bytes::copy_from_slice(image.as_ref())),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RejectForm {
+    #[serde(default)]
+    reason: String,
+}
+
+pub async fn approve(
+    path: web::Path<(u32, u32)>,
+    query: web::Query<SlotQuery>,
+    data: web::Data<AppState>,
+    member: meta::Member,
+) -> actix_web::Result<HttpResponse> {
+    let (char_id, ver) = path.into_inner();
+    let moderator = member.id;
+    let (bark, slot) = query.bark(&data)?;
+    let root = data.sled_db.root.clone();
+    let publish = blocking(move || {
+        let trunk = root.trunk(char_id, None, bark);
+        trunk.moderate_image(ver, ModerationState::Approved, moderator, None)?;
+        // approving an old upload shouldn't replace newer avatar in the game
+        if trunk.has_newer_published(ver)? {
+            return Ok(None);
+        }
+        trunk.get_image_secret(ver)
+    })
+    .await
+    .map_err(moderation_error)?;
+    let name = slot.as_deref().unwrap_or("Avatar");
+    println!("{} {} of {} approved by {}", name, ver, char_id, moderator);
+
+    let leaf = Leaf {
+        data: (),
+        ver,
+        secret: publish,
+    };
+    let sender = data.bridge.get_sender();
+    match &slot {
+        Some(slot) => image::update_char_image(sender, char_id, slot, leaf),
+        None => avatar::update_char_leaf(sender, char_id, leaf),
+    }
+    .map_err(internal_error)?;
+    Ok(back_to_queue())
+}
+
+pub async fn reject(
+    path: web::Path<(u32, u32)>,
+    query: web::Query<SlotQuery>,
+    form: web::Form<RejectForm>,
+    data: web::Data<AppState>,
+    member: meta::Member,
+) -> actix_web::Result<HttpResponse> {
+    let (char_id, ver) = path.into_inner();
+    let moderator = member.id;
+    let reason = form.into_inner().reason.trim().to_owned();
+    if reason.is_empty() {
+        return Err(meta::bad_request("Reason is required")().into());
+    }
+    let (bark, slot) = query.bark(&data)?;
+    let root = data.sled_db.root.clone();
+    blocking(move || {
+        let trunk = root.trunk(char_id, None, bark);
+        trunk.moderate_image(ver, ModerationState::Rejected, moderator, Some(reason))
+    })
+    .await
+    .map_err(moderation_error)?;
+    let name = slot.as_deref().unwrap_or("Avatar");
+    println!("{} {} of {} rejected by {}", name, ver, char_id, moderator);
+
+    Ok(back_to_queue())
+}
+
+fn moderation_error(err: VersionedError) -> actix_web::Error {
+    match err {
+        VersionedError::NotFound => meta::not_found("Unknown version")().into(),
+        VersionedError::UnexpectedOldValue => {
+            meta::conflict("Version is already moderated")().into()
+        }
+        err => internal_error(err),
+    }
+}
+
+fn back_to_queue() -> HttpResponse {
+    HttpResponse::SeeOther()
+        .append_header((header::LOCATION, QUEUE_URL))
+        .finish()
+}
+
+// ===== ModerationError =====
+
+#[derive(Debug)]
+enum ModerationError {
+    Versioned(VersionedError),
+    Template(templates::TemplatesError),
+    Blocking,
+}
+
+impl From<VersionedError> for ModerationError {
+    fn from(err: VersionedError) -> Self {
+        ModerationError::Versioned(err)
+    }
+}
+
+impl From<actix_web::error::BlockingError> for ModerationError {
+    fn from(_err: actix_web::error::BlockingError) -> Self {
+        ModerationError::Blocking
+    }
+}
@@ -52,51 +52,54 @@ where
 }
 */
 
-pub fn restrict<F>(rule: fn(HttpRequest) -> F) -> RestrictWrapper<F>
+pub fn restrict<R, F>(rule: R) -> RestrictWrapper<R>
 where
+    R: 'static + Clone + Fn(HttpRequest) -> F,
     F: 'static + Future<Output = Result<Restrict, actix_web::Error>>,
 {
     RestrictWrapper { rule }
 }
 
-pub struct RestrictWrapper<F> {
-    rule: fn(HttpRequest) -> F,
+pub struct RestrictWrapper<R> {
+    rule: R,
 }
 
-impl<S, F> Transform<S, ServiceRequest> for RestrictWrapper<F>
+impl<S, R, F> Transform<S, ServiceRequest> for RestrictWrapper<R>
 where
     S: 'static + Service<ServiceRequest, Response = ServiceResponse>,
     S::Future: 'static,
     // TODO: Remove actix_web::Error bound
     S::Error: 'static + From<InternalError<String>> + From<actix_web::Error>,
+    R: 'static + Clone + Fn(HttpRequest) -> F,
     F: 'static + Future<Output = Result<Restrict, actix_web::Error>>,
 {
     type Error = S::Error;
     type Future = fut::Ready<Result<Self::Transform, Self::InitError>>;
     type InitError = ();
     type Response = ServiceResponse;
-    type Transform = RestrictMiddleware<S, F>;
+    type Transform = RestrictMiddleware<S, R>;
 
     fn new_transform(&self, service: S) -> Self::Future {
         fut::ok(RestrictMiddleware {
             service: Rc::new(service),
-            rule: self.rule,
+            rule: self.rule.clone(),
         })
     }
 }
 
 /// Cookie based session middleware.
-pub struct RestrictMiddleware<S, F> {
+pub struct RestrictMiddleware<S, R> {
     service: Rc<S>,
-    rule: fn(HttpRequest) -> F,
+    rule: R,
 }
 
-impl<S, F> Service<ServiceRequest> for RestrictMiddleware<S, F>
+impl<S, R, F> Service<ServiceRequest> for RestrictMiddleware<S, R>
 where
     S: 'static + Service<ServiceRequest, Response = ServiceResponse>,
     S::Future: 'static,
     // TODO: Remove actix_web::Error bound
     S::Error: 'static + From<InternalError<String>> + From<actix_web::Error>,
+    R: 'static + Fn(HttpRequest) -> F,
     F: 'static + Future<Output = Result<Restrict, actix_web::Error>>,
 {
     type Error = S::Error;
@@ -107,11 +110,11 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let service = self.service.clone();
-        let rule = self.rule;
+        let (http_req, payload) = req.into_parts();
+        let rule = (self.rule)(http_req.clone());
         async move {
-            let (http_req, payload) = req.into_parts();
             //let req = ServiceRequest::from_parts(http_req.clone(), payload);
-            match (rule)(http_req.clone()).await {
+            match rule.await {
                 Ok(Restrict::Allow) => {
                     let req = ServiceRequest::from_parts(http_req, payload);
                     service.call(req).await
@@ -0,0 +1,99 @@
+use std::{collections::HashMap, convert::TryFrom, time::Duration};
+
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration as CookieDuration;
+
+use super::meta;
+use crate::{
+    database::{session, Root},
+    utils::blocking,
+};
+
+/// Keeps only random key in the cookie, session state lives in sled,
+/// so sessions can be listed and revoked.
+#[derive(Clone)]
+pub struct SledSessionStore {
+    root: Root,
+    ttl: Duration,
+}
+
+impl SledSessionStore {
+    pub fn new(root: Root, ttl: Duration) -> Self {
+        SledSessionStore { root, ttl }
+    }
+
+    async fn store(
+        &self,
+        key: String,
+        state: HashMap<String, String>,
+        only_existing: bool,
+    ) -> Result<bool, anyhow::Error> {
+        let root = self.root.clone();
+        let ttl = self.ttl;
+        let user_id = meta::session_user_id(&state);
+        blocking(move || session::store_session(&root, &key, user_id, state, ttl, only_existing))
+            .await
+            .map_err(|err| anyhow::anyhow!("Can't store session: {:?}", err))
+    }
+}
+
+fn session_key(key: String) -> Result<SessionKey, anyhow::Error> {
+    SessionKey::try_from(key).map_err(|_| anyhow::anyhow!("Invalid session key"))
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SledSessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let root = self.root.clone();
+        let key = session_key.as_ref().to_owned();
+        let ttl = self.ttl;
+        blocking(move || session::load_session(&root, &key, ttl))
+            .await
+            .map_err(|err| LoadError::Other(anyhow::anyhow!("Can't load session: {:?}", err)))
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        _ttl: &CookieDuration,
+    ) -> Result<SessionKey, SaveError> {
+        let key = session::new_session_key();
+        self.store(key.clone(), session_state, false)
+            .await
+            .map_err(SaveError::Other)?;
+        session_key(key).map_err(SaveError::Other)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        _ttl: &CookieDuration,
+    ) -> Result<SessionKey, UpdateError> {
+        let key = session_key.as_ref().to_owned();
+        let updated = self
+            .store(key, session_state, true)
+            .await
+            .map_err(UpdateError::Other)?;
+        if updated {
+            return Ok(session_key);
+        }
+        // revoked while the request was handled, start a new empty session instead of reviving it
+        let key = session::new_session_key();
+        self.store(key.clone(), HashMap::new(), false)
+            .await
+            .map_err(UpdateError::Other)?;
+        self::session_key(key).map_err(UpdateError::Other)
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        let root = self.root.clone();
+        let key = session_key.as_ref().to_owned();
+        blocking(move || session::remove_session(&root, &key))
+            .await
+            .map_err(|err| anyhow::anyhow!("Can't delete session: {:?}", err))
+    }
+}
@@ -5,13 +5,14 @@ use fo_defines_fo4rp::param::Param;
 use futures::FutureExt;
 use serde::Serialize;
 
-use super::AppState;
-use crate::{config::Host, templates};
+use super::{meta, AppState};
+use crate::templates;
 
 // TODO: Rewrite
 pub async fn gm_stats(
     req: HttpRequest,
     data: web::Data<AppState>,
+    impersonating: meta::Impersonating,
 ) -> actix_web::Result<HttpResponse> {
     let name = req.match_info().get("client").and_then(|client| {
         percent_encoding::percent_decode(client.as_bytes())
@@ -25,7 +26,8 @@ pub async fn gm_stats(
             .map(|res| {
                 match res {
                     //Ok(Some(cr_info)) => Ok(format!("Your info: {:?}", cr_info).into()),
-                    Ok(Ok((cr_info, data))) => match Stats::new(&cr_info).render(&data.config.host)
+                    Ok(Ok((cr_info, data))) => match Stats::new(&cr_info)
+                        .render(impersonating.render_config(&data.config.host))
                     {
                         Ok(body) => Ok(HttpResponse::Ok().content_type("text/html").body(body)),
                         Err(err) => {
@@ -55,12 +57,8 @@ struct Stats<'a> {
 }
 
 impl<'a> Stats<'a> {
-    fn render(&self, host: &Host) -> Result<String, templates::TemplatesError> {
-        templates::render(
-            "charsheet.html",
-            self,
-            templates::RenderConfig { host: Some(host) },
-        )
+    fn render(&self, config: templates::RenderConfig) -> Result<String, templates::TemplatesError> {
+        templates::render("charsheet.html", self, config)
     }
 }
 
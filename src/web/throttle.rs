@@ -0,0 +1,123 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use actix_session::SessionExt;
+use actix_web::{
+    http::{header, Method},
+    web, HttpRequest, HttpResponse,
+};
+use futures::future::{self as fut, Ready};
+
+use super::{meta, restrict::Restrict, AppState};
+use crate::{
+    config::{self, LimitKey},
+    rate_limit::RateLimiter,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Throttle {
+    Login,
+    Auth,
+    /// Every authkey check: claims and authkey logins
+    Claim,
+    /// Only POST requests are counted
+    Upload,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ThrottleKey {
+    ip: Option<IpAddr>,
+    user_id: Option<u64>,
+}
+
+pub struct Throttles {
+    login: RateLimiter<ThrottleKey>,
+    auth: RateLimiter<ThrottleKey>,
+    claim: RateLimiter<ThrottleKey>,
+    upload: RateLimiter<ThrottleKey>,
+}
+
+impl Throttles {
+    pub fn new(config: &config::RateLimit) -> Self {
+        Throttles {
+            login: RateLimiter::new(config.login.clone()),
+            auth: RateLimiter::new(config.auth.clone()),
+            claim: RateLimiter::new(config.claim.clone()),
+            upload: RateLimiter::new(config.upload.clone()),
+        }
+    }
+
+    fn limiter(&self, throttle: Throttle) -> &RateLimiter<ThrottleKey> {
+        match throttle {
+            Throttle::Login => &self.login,
+            Throttle::Auth => &self.auth,
+            Throttle::Claim => &self.claim,
+            Throttle::Upload => &self.upload,
+        }
+    }
+}
+
+fn client_ip(req: &HttpRequest, trust_forwarded: bool) -> Option<IpAddr> {
+    if trust_forwarded {
+        let forwarded = req.connection_info().realip_remote_addr().and_then(|addr| {
+            addr.parse::<IpAddr>()
+                .ok()
+                .or_else(|| addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        });
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    req.peer_addr().map(|addr| addr.ip())
+}
+
+pub fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    HttpResponse::TooManyRequests()
+        .append_header((header::RETRY_AFTER, secs.to_string()))
+        .content_type("text/plain; charset=utf-8")
+        .body(format!("Too many requests, retry after {} s", secs))
+}
+
+/// Takes a token for the request, `Err` holds `429 Too Many Requests` response
+pub fn check(
+    data: &AppState,
+    req: &HttpRequest,
+    throttle: Throttle,
+    user_id: Option<u64>,
+) -> Result<(), HttpResponse> {
+    let limiter = data.throttles.limiter(throttle);
+    let ip = client_ip(req, data.config.rate_limit.trust_forwarded);
+    let key = match (limiter.limit().key, user_id) {
+        (LimitKey::Ip, _) | (LimitKey::User, None) => ThrottleKey { ip, user_id: None },
+        (LimitKey::User, Some(user_id)) => ThrottleKey {
+            ip: None,
+            user_id: Some(user_id),
+        },
+        (LimitKey::IpAndUser, user_id) => ThrottleKey { ip, user_id },
+    };
+    limiter.check(key).map_err(|retry_after| {
+        eprintln!("{:?} rate limit hit by {:?} {:?}", throttle, ip, user_id);
+        too_many_requests(retry_after)
+    })
+}
+
+/// Rule for `restrict` middleware, counts requests of the logged in user or of the IP
+pub fn restrict_rate(
+    throttle: Throttle,
+) -> impl Clone + Fn(HttpRequest) -> Ready<Result<Restrict, actix_web::Error>> {
+    move |req| {
+        if let (Throttle::Upload, true) = (throttle, req.method() == &Method::GET) {
+            return fut::ok(Restrict::Allow);
+        }
+        let data: &web::Data<AppState> = req.app_data().expect("AppData");
+        let user_id = meta::get_user_id(&req.get_session());
+        let res = check(data, &req, throttle, user_id);
+        fut::ok(match res {
+            Ok(()) => Restrict::Allow,
+            Err(response) => Restrict::response(req, response),
+        })
+    }
+}